@@ -0,0 +1,458 @@
+use jojo_common::device::DeviceId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+// An address block like 192.168.1.0/24, a plain address is a block of one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(addr), IpAddr::V4(ip)) => prefix_matches(
+                u32::from(addr) as u128,
+                u32::from(ip) as u128,
+                self.prefix,
+                32,
+            ),
+            (IpAddr::V6(addr), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(addr), u128::from(ip), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(addr: u128, ip: u128, prefix: u8, bits: u8) -> bool {
+    let shift = (bits - prefix) as u32;
+
+    addr.checked_shr(shift).unwrap_or(0) == ip.checked_shr(shift).unwrap_or(0)
+}
+
+// Dual stack listeners report ipv4 peers as ::ffff:a.b.c.d
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = value.split_once('/').unwrap_or((value, ""));
+        let addr = canonical(
+            addr.trim()
+                .parse::<IpAddr>()
+                .map_err(|err| format!("bad address in {}: {}", value, err))?,
+        );
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix.trim() {
+            "" => bits,
+            prefix => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= bits)
+                .ok_or_else(|| format!("bad prefix in {}", value))?,
+        };
+
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccessConfig {
+    // Empty allows every address that isn't denied
+    pub allow: Vec<Cidr>,
+    // Wins over allow
+    pub deny: Vec<Cidr>,
+    pub max_devices: usize,
+    pub max_connections_per_ip: usize,
+    // Origins of the web pages that can open sockets, like http://localhost:1420. "*" allows any
+    pub allowed_origins: Vec<String>,
+    // Asked by /control, the dashboard and its apis on the network listener, the local socket doesn't need it.
+    // Without one they are only served on the local socket.
+    pub control_token: Option<String>,
+}
+
+impl Default for AccessConfig {
+    fn default() -> Self {
+        AccessConfig {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            max_devices: 32,
+            max_connections_per_ip: 4,
+            allowed_origins: Vec::new(),
+            control_token: None,
+        }
+    }
+}
+
+impl AccessConfig {
+    pub fn check(&self, ip: IpAddr) -> Result<(), Rejection> {
+        let ip = canonical(ip);
+
+        if let Some(cidr) = self.deny.iter().find(|cidr| cidr.contains(ip)) {
+            return Err(Rejection::Denied(ip, *cidr));
+        }
+
+        if !self.allow.is_empty() && !self.allow.iter().any(|cidr| cidr.contains(ip)) {
+            return Err(Rejection::NotAllowed(ip));
+        }
+
+        Ok(())
+    }
+
+    pub fn check_token(&self, token: Option<&str>) -> Result<(), Rejection> {
+        let Some(expected) = self
+            .control_token
+            .as_deref()
+            .filter(|token| !token.is_empty())
+        else {
+            return Err(Rejection::NoToken);
+        };
+
+        match token.is_some_and(|token| same_bytes(token.as_bytes(), expected.as_bytes())) {
+            true => Ok(()),
+            false => Err(Rejection::BadToken),
+        }
+    }
+
+    // Browsers always send an Origin, so any page the user visits could reach the LAN server through them.
+    // Devices and the app don't send one. Host is the header of the same request.
+    pub fn check_origin(&self, origin: Option<&str>, host: Option<&str>) -> Result<(), Rejection> {
+        let Some(origin) = origin else {
+            return Ok(());
+        };
+
+        let allowed = self.allowed_origins.iter().any(|allowed| {
+            allowed == "*" || allowed.trim_end_matches('/').eq_ignore_ascii_case(origin)
+        }) || host.is_some_and(|host| same_origin(origin, host));
+
+        match allowed {
+            true => Ok(()),
+            false => Err(Rejection::Origin(origin.to_string())),
+        }
+    }
+}
+
+// Takes as long for every token of the same length, so the answer time doesn't tell how much of it matched
+fn same_bytes(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// Pages served by the server itself, like the dashboard. Only when it's reached by address, a name could have been
+// rebound to the server by another site.
+fn same_origin(origin: &str, host: &str) -> bool {
+    let Some((_, authority)) = origin.split_once("://") else {
+        return false;
+    };
+
+    if !authority.eq_ignore_ascii_case(host) {
+        return false;
+    }
+
+    let hostname = match host.rsplit_once(':') {
+        Some((hostname, port)) if port.chars().all(|c| c.is_ascii_digit()) => hostname,
+        _ => host,
+    };
+    let hostname = hostname.trim_start_matches('[').trim_end_matches(']');
+
+    hostname.eq_ignore_ascii_case("localhost") || hostname.parse::<IpAddr>().is_ok()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    Denied(IpAddr, Cidr),
+    NotAllowed(IpAddr),
+    TooManyDevices(usize),
+    TooManyConnections(IpAddr, usize),
+    Origin(String),
+    // The listener has no control token configured
+    NoToken,
+    BadToken,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::Denied(ip, cidr) => write!(f, "{} is denied by {}", ip, cidr),
+            Rejection::NotAllowed(ip) => write!(f, "{} is not in the allow list", ip),
+            Rejection::TooManyDevices(max) => write!(f, "already {} devices connected", max),
+            Rejection::TooManyConnections(ip, max) => {
+                write!(f, "already {} connections from {}", max, ip)
+            }
+            Rejection::Origin(origin) => write!(f, "origin {} is not allowed", origin),
+            Rejection::NoToken => write!(f, "no control token is configured for the network"),
+            Rejection::BadToken => write!(f, "missing or wrong control token"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Connections {
+    // Open sockets of each device, a device reconnecting before its old socket is closed is still one device
+    devices: HashMap<DeviceId, usize>,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+// Counts the open sockets, a connection is counted until its guard is dropped
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionTracker {
+    connections: Arc<Mutex<Connections>>,
+}
+
+impl ConnectionTracker {
+    // Connections without an address come from the local socket, only the device limit applies to them. Control
+    // sockets have no device and only count against their address
+    pub fn admit(
+        &self,
+        ip: Option<IpAddr>,
+        device_id: Option<DeviceId>,
+        config: &AccessConfig,
+    ) -> Result<ConnectionGuard, Rejection> {
+        let ip = ip.map(canonical);
+
+        if let Some(ip) = ip {
+            config.check(ip)?;
+        }
+
+        let mut connections = self.connections.lock().unwrap();
+
+        if let Some(device_id) = device_id {
+            if !connections.devices.contains_key(&device_id)
+                && connections.devices.len() >= config.max_devices
+            {
+                return Err(Rejection::TooManyDevices(config.max_devices));
+            }
+        }
+
+        if let Some(ip) = ip {
+            let count = connections.per_ip.entry(ip).or_default();
+
+            if *count >= config.max_connections_per_ip {
+                return Err(Rejection::TooManyConnections(
+                    ip,
+                    config.max_connections_per_ip,
+                ));
+            }
+
+            *count += 1;
+        }
+
+        if let Some(device_id) = device_id {
+            *connections.devices.entry(device_id).or_default() += 1;
+        }
+
+        Ok(ConnectionGuard {
+            ip,
+            device_id,
+            connections: self.connections.clone(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    ip: Option<IpAddr>,
+    device_id: Option<DeviceId>,
+    connections: Arc<Mutex<Connections>>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut connections = self.connections.lock().unwrap();
+
+        if let Some(device_id) = self.device_id {
+            decrement(&mut connections.devices, device_id);
+        }
+
+        if let Some(ip) = self.ip {
+            decrement(&mut connections.per_ip, ip);
+        }
+    }
+}
+
+fn decrement<K: std::hash::Hash + Eq>(counts: &mut HashMap<K, usize>, key: K) {
+    if let Some(count) = counts.get_mut(&key) {
+        *count -= 1;
+
+        if *count == 0 {
+            counts.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr() {
+        let lan: Cidr = "192.168.1.0/24".parse().unwrap();
+
+        assert!(lan.contains(ip("192.168.1.77")));
+        assert!(lan.contains(ip("::ffff:192.168.1.77")));
+        assert!(!lan.contains(ip("192.168.2.1")));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("8.8.8.8")));
+        assert!("fd00::/8".parse::<Cidr>().unwrap().contains(ip("fd12::1")));
+        assert!("10.0.0.1".parse::<Cidr>().unwrap().contains(ip("10.0.0.1")));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert_eq!(
+            serde_json::from_str::<Cidr>("\"10.0.0.0/8\"")
+                .unwrap()
+                .to_string(),
+            "10.0.0.0/8"
+        );
+    }
+
+    #[test]
+    fn test_origin() {
+        let config = AccessConfig {
+            allowed_origins: vec!["http://localhost:1420/".to_string()],
+            ..Default::default()
+        };
+
+        assert!(config.check_origin(None, None).is_ok());
+        assert!(config
+            .check_origin(Some("http://localhost:1420"), None)
+            .is_ok());
+        assert_eq!(
+            config.check_origin(Some("https://evil.example"), Some("192.168.1.2:8080")),
+            Err(Rejection::Origin("https://evil.example".to_string()))
+        );
+        assert!(AccessConfig::default()
+            .check_origin(Some("http://localhost:1420"), None)
+            .is_err());
+
+        // Dashboard served by the server
+        let config = AccessConfig::default();
+
+        assert!(config
+            .check_origin(Some("http://192.168.1.2:8080"), Some("192.168.1.2:8080"))
+            .is_ok());
+        assert!(config
+            .check_origin(Some("http://[::1]:8080"), Some("[::1]:8080"))
+            .is_ok());
+        // Rebound name
+        assert!(config
+            .check_origin(Some("http://evil.example:8080"), Some("evil.example:8080"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_token() {
+        assert_eq!(
+            AccessConfig::default().check_token(Some("")),
+            Err(Rejection::NoToken)
+        );
+
+        let config = AccessConfig {
+            control_token: Some("secret".to_string()),
+            ..Default::default()
+        };
+
+        assert!(config.check_token(Some("secret")).is_ok());
+        assert_eq!(config.check_token(Some("secreT")), Err(Rejection::BadToken));
+        assert_eq!(
+            config.check_token(Some("secret2")),
+            Err(Rejection::BadToken)
+        );
+        assert_eq!(config.check_token(None), Err(Rejection::BadToken));
+    }
+
+    #[test]
+    fn test_admit() {
+        let config = AccessConfig {
+            allow: vec!["192.168.1.0/24".parse().unwrap()],
+            deny: vec!["192.168.1.13".parse().unwrap()],
+            max_devices: 2,
+            max_connections_per_ip: 1,
+            ..Default::default()
+        };
+        let tracker = ConnectionTracker::default();
+        let (first, local) = (DeviceId::new_v4(), DeviceId::new_v4());
+
+        assert!(matches!(
+            tracker.admit(Some(ip("192.168.1.13")), Some(first), &config),
+            Err(Rejection::Denied(..))
+        ));
+        assert!(matches!(
+            tracker.admit(Some(ip("10.0.0.1")), Some(first), &config),
+            Err(Rejection::NotAllowed(_))
+        ));
+
+        let first_guard = tracker
+            .admit(Some(ip("192.168.1.2")), Some(first), &config)
+            .unwrap();
+
+        assert!(matches!(
+            tracker.admit(Some(ip("192.168.1.2")), Some(DeviceId::new_v4()), &config),
+            Err(Rejection::TooManyConnections(..))
+        ));
+        // Control sockets count against their address
+        assert!(matches!(
+            tracker.admit(Some(ip("192.168.1.2")), None, &config),
+            Err(Rejection::TooManyConnections(..))
+        ));
+
+        let _local = tracker.admit(None, Some(local), &config).unwrap();
+
+        assert!(matches!(
+            tracker.admit(Some(ip("192.168.1.3")), Some(DeviceId::new_v4()), &config),
+            Err(Rejection::TooManyDevices(2))
+        ));
+        // A device replacing its own socket is not a new device
+        let replaced = tracker.admit(None, Some(local), &config).unwrap();
+        assert!(tracker
+            .admit(Some(ip("192.168.1.3")), None, &config)
+            .is_ok());
+
+        drop(replaced);
+
+        assert!(matches!(
+            tracker.admit(Some(ip("192.168.1.3")), Some(DeviceId::new_v4()), &config),
+            Err(Rejection::TooManyDevices(2))
+        ));
+
+        drop(first_guard);
+
+        assert!(tracker
+            .admit(Some(ip("192.168.1.2")), Some(DeviceId::new_v4()), &config)
+            .is_ok());
+    }
+}
@@ -0,0 +1,309 @@
+// Fake jojo-client, it drives the server without flashing a device.
+//
+// jojo-sim <ws://host:port> [--id <uuid>] [--device <device.json>] [--script <file>]
+//          [--encoding <bincode|json|msgpack|cbor>]
+//
+// Without a script the commands are read from stdin, one per line:
+//   mouse <x> <y>
+//   axis <axis> <value>       e.g. axis X 16000
+//   hat <hat> <value>
+//   keys <sequence>
+//   button <uuid> <down|up>   raw button event, resolved by the server mappings
+//   sleep <millis>
+//   {...}                     any ClientMessage as json
+//   # comment
+use futures_util::{SinkExt, StreamExt};
+use jojo_common::button::ButtonAction;
+use jojo_common::device::{Device, DeviceId};
+use jojo_common::keyboard::KeyboardButton;
+use jojo_common::message::ClientMessage;
+use jojo_server::protocol::{
+    self, Capability, Encoding, ExtClientMessage, Hello, Outbound, PROTOCOL_VERSION,
+};
+use log::*;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+
+const CLOSE_MILLIS: u64 = 1_000;
+
+#[derive(Debug, Default)]
+struct Options {
+    url: String,
+    device_id: Option<DeviceId>,
+    device: Option<PathBuf>,
+    script: Option<PathBuf>,
+    encoding: Encoding,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> anyhow::Result<Self> {
+        let mut options = Options::default();
+
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| anyhow::anyhow!("{} needs a value", arg))
+            };
+
+            match arg.as_str() {
+                "--id" => options.device_id = Some(value()?.parse()?),
+                "--device" => options.device = Some(value()?.into()),
+                "--script" => options.script = Some(value()?.into()),
+                "--encoding" => {
+                    let value = value()?;
+
+                    options.encoding = Encoding::from_subprotocols(&format!("jojo.{}", value))
+                        .ok_or_else(|| anyhow::anyhow!("unknown encoding {}", value))?;
+                }
+                _ if options.url.is_empty() => options.url = arg,
+                _ => anyhow::bail!("unknown argument {}", arg),
+            }
+        }
+
+        if options.url.is_empty() {
+            anyhow::bail!("usage: jojo-sim <ws://host:port> [--id <uuid>] [--device <device.json>] [--script <file>] [--encoding <bincode|json|msgpack|cbor>]");
+        }
+
+        Ok(options)
+    }
+}
+
+#[derive(Debug)]
+enum Command {
+    Send(Message),
+    Sleep(Duration),
+}
+
+// Json travels in text frames
+fn frame(encoding: Encoding, bytes: Vec<u8>) -> anyhow::Result<Message> {
+    Ok(match encoding.is_text() {
+        true => Message::Text(String::from_utf8(bytes)?),
+        false => Message::Binary(bytes),
+    })
+}
+
+fn client_message(encoding: Encoding, message: &ClientMessage) -> anyhow::Result<Option<Command>> {
+    Ok(Some(Command::Send(frame(
+        encoding,
+        protocol::encode_client_as(encoding, message)?,
+    )?)))
+}
+
+// Numbers and json stay as they are, anything else is taken as a variant name
+fn json_value(word: &str) -> Value {
+    serde_json::from_str(word).unwrap_or_else(|_| Value::String(word.to_string()))
+}
+
+fn parse_line(encoding: Encoding, line: &str) -> anyhow::Result<Option<Command>> {
+    let line = line.trim();
+
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    if line.starts_with('{') {
+        return client_message(encoding, &serde_json::from_str(line)?);
+    }
+
+    let words: Vec<&str> = line.split_whitespace().collect();
+
+    // Messages built from json, so the simulator doesn't depend on how jojo_common constructs them
+    let message = match words.as_slice() {
+        ["sleep", millis] => {
+            return Ok(Some(Command::Sleep(Duration::from_millis(millis.parse()?))))
+        }
+        ["button", button_id, state] => {
+            let pressed = match *state {
+                "down" => true,
+                "up" => false,
+                _ => anyhow::bail!("button state must be down or up"),
+            };
+            let message = ExtClientMessage::ButtonEvent(button_id.parse()?, pressed);
+
+            return Ok(Some(Command::Send(frame(
+                encoding,
+                protocol::encode_extension_as(encoding, &message)?,
+            )?)));
+        }
+        ["keys", ..] => {
+            let sequence = line["keys".len()..].trim().to_string();
+
+            return client_message(
+                encoding,
+                &ClientMessage::ButtonActions(vec![ButtonAction::KeyboardButton(
+                    KeyboardButton::Sequence(sequence),
+                )]),
+            );
+        }
+        // As a sequence, serde takes it for a struct in field order without relying on the field names
+        ["mouse", x, y] => json!({ "MouseRead": [x.parse::<i64>()?, y.parse::<i64>()?] }),
+        ["axis", axis, value] => json!({ "AxisRead": [json_value(axis), value.parse::<i64>()?] }),
+        ["hat", hat, value] => json!({ "HatRead": [json_value(hat), json_value(value)] }),
+        _ => anyhow::bail!("unknown command: {}", line),
+    };
+
+    client_message(encoding, &serde_json::from_value(message)?)
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    dotenv::dotenv().ok();
+    pretty_env_logger::init();
+
+    let options = Options::parse(std::env::args().skip(1))?;
+    let encoding = options.encoding;
+    let device_id = options.device_id.unwrap_or_else(DeviceId::new_v4);
+    let url = format!("{}/ws/{}", options.url.trim_end_matches('/'), device_id);
+
+    // The subprotocol picks the encoding before the first frame, the hello only confirms it
+    let mut request = url.as_str().into_client_request()?;
+    request
+        .headers_mut()
+        .insert("Sec-WebSocket-Protocol", encoding.subprotocol().parse()?);
+
+    let (socket, _) = tokio_tungstenite::connect_async(request).await?;
+    info!("[jojo-sim]: connected to {}", url);
+
+    let (mut tx, mut rx) = socket.split();
+    let (sender_tx, mut sender_rx) = tokio::sync::mpsc::channel::<Message>(32);
+    let pong_tx = sender_tx.clone();
+
+    let msg_sender = tokio::spawn(async move {
+        while let Some(msg) = sender_rx.recv().await {
+            if let Err(err) = tx.send(msg).await {
+                error!("[jojo-sim]: cannot send msg, err: {}", err);
+                break;
+            }
+        }
+    });
+
+    let read_socket = tokio::spawn(async move {
+        while let Some(result) = rx.next().await {
+            match result {
+                Ok(Message::Binary(bytes)) => print_outbound(encoding, &bytes),
+                Ok(Message::Text(text)) => print_outbound(encoding, text.as_bytes()),
+                // The server closes the socket when pongs stop arriving
+                Ok(Message::Ping(payload)) => pong_tx
+                    .send(Message::Pong(payload))
+                    .await
+                    .unwrap_or_else(|_| info!("[jojo-sim]: pong_tx send error")),
+                Ok(Message::Close(_)) => {
+                    info!("[jojo-sim]: closed by the server");
+                    break;
+                }
+                Ok(message) => info!("[jojo-sim]: {:?}", message),
+                Err(err) => {
+                    error!("[jojo-sim]: message error: {}", err);
+                    break;
+                }
+            }
+        }
+    });
+
+    // Everything is printed, so every extension is welcome
+    let hello = ExtClientMessage::Hello(Hello {
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: Capability::ALL.to_vec(),
+        encodings: vec![encoding],
+    });
+    sender_tx
+        .send(frame(
+            encoding,
+            protocol::encode_extension_as(encoding, &hello)?,
+        )?)
+        .await?;
+
+    let device = match &options.device {
+        Some(path) => serde_json::from_str(&tokio::fs::read_to_string(path).await?)?,
+        None => Device::default(),
+    };
+
+    if let Some(Command::Send(message)) = client_message(encoding, &ClientMessage::Device(device))?
+    {
+        sender_tx.send(message).await?;
+    }
+
+    let input: Box<dyn AsyncBufRead + Unpin + Send> = match &options.script {
+        Some(path) => Box::new(BufReader::new(tokio::fs::File::open(path).await?)),
+        None => Box::new(BufReader::new(tokio::io::stdin())),
+    };
+    let mut lines = input.lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if read_socket.is_finished() {
+            break;
+        }
+
+        match parse_line(encoding, &line) {
+            Ok(Some(Command::Send(message))) => sender_tx.send(message).await?,
+            Ok(Some(Command::Sleep(duration))) => tokio::time::sleep(duration).await,
+            Ok(None) => {}
+            Err(err) => eprintln!("{}", err),
+        }
+    }
+
+    // Give the server a moment to answer the last messages
+    sender_tx.send(Message::Close(None)).await.ok();
+    tokio::time::timeout(Duration::from_millis(CLOSE_MILLIS), read_socket)
+        .await
+        .ok();
+    msg_sender.abort();
+
+    Ok(())
+}
+
+fn print_outbound(encoding: Encoding, bytes: &[u8]) {
+    match Outbound::decode_as(encoding, bytes) {
+        Ok(Outbound::Server(message)) => println!("<- {:?}", message),
+        Ok(Outbound::Extension(message)) => println!("<- {:?}", message),
+        Err(err) => error!("[jojo-sim]: deserialize {:?}: {}", encoding, err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jojo_server::protocol::Inbound;
+
+    #[test]
+    fn test_parse_line() {
+        let button_id = DeviceId::new_v4();
+
+        assert!(matches!(
+            parse_line(Encoding::Bincode, "# comment"),
+            Ok(None)
+        ));
+        assert!(matches!(
+            parse_line(Encoding::Bincode, "sleep 20"),
+            Ok(Some(Command::Sleep(duration))) if duration == Duration::from_millis(20)
+        ));
+        assert!(parse_line(Encoding::Bincode, "jump").is_err());
+
+        let Ok(Some(Command::Send(Message::Binary(bytes)))) =
+            parse_line(Encoding::Bincode, &format!("button {} down", button_id))
+        else {
+            panic!("button event not parsed");
+        };
+
+        assert!(matches!(
+            protocol::decode_frame(Encoding::Bincode, &bytes, false),
+            Ok(Inbound::Extension(ExtClientMessage::ButtonEvent(id, true))) if id == button_id
+        ));
+
+        // Json goes out as text
+        let Ok(Some(Command::Send(Message::Text(text)))) =
+            parse_line(Encoding::Json, &format!("button {} up", button_id))
+        else {
+            panic!("button event not parsed");
+        };
+
+        assert!(matches!(
+            protocol::decode_frame(Encoding::Json, text.as_bytes(), true),
+            Ok(Inbound::Extension(ExtClientMessage::ButtonEvent(id, false))) if id == button_id
+        ));
+    }
+}
@@ -0,0 +1,198 @@
+use serde::{Deserialize, Serialize};
+
+// vJoy axis range, devices that already send it don't need any calibration
+pub const AXIS_MIN: f64 = 0.0;
+pub const AXIS_MAX: f64 = 32_767.0;
+
+// Axes are keyed by their name ("X", "Ry", ...) so the config stays readable as json
+pub type AxisKey = String;
+
+pub fn axis_key(axis: &impl Serialize) -> AxisKey {
+    serde_name(axis)
+}
+
+// The name a value has in json, unit variants like axes and hat directions serialize to a plain string
+pub fn serde_name(value: &impl Serialize) -> String {
+    match serde_json::to_value(value).unwrap_or_default() {
+        serde_json::Value::String(name) => name,
+        value => value.to_string(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AxisCalibration {
+    // Raw values reported by the device
+    pub min: f64,
+    pub max: f64,
+    pub center: f64,
+    // Fraction of each half around the center that reads as centered
+    pub deadzone: f64,
+    // Fraction of each half where the output already reaches the end
+    pub saturation: f64,
+    pub invert: bool,
+    // 0 is linear, 1 is fully cubic
+    pub expo: f64,
+}
+
+impl Default for AxisCalibration {
+    fn default() -> Self {
+        AxisCalibration {
+            min: AXIS_MIN,
+            max: AXIS_MAX,
+            center: (AXIS_MIN + AXIS_MAX) / 2.0,
+            deadzone: 0.0,
+            saturation: 1.0,
+            invert: false,
+            expo: 0.0,
+        }
+    }
+}
+
+impl AxisCalibration {
+    pub fn apply(&self, raw: f64) -> f64 {
+        let half = match raw >= self.center {
+            true => self.max - self.center,
+            false => self.center - self.min,
+        };
+
+        // Normalized to -1..1 around the center
+        let normalized = match half > 0.0 {
+            true => ((raw - self.center) / half).clamp(-1.0, 1.0),
+            false => 0.0,
+        };
+
+        let magnitude = normalized.abs();
+        let deadzone = self.deadzone.clamp(0.0, 1.0);
+        let saturation = self.saturation.clamp(deadzone, 1.0);
+
+        let scaled = match saturation - deadzone > 0.0 {
+            true => ((magnitude - deadzone) / (saturation - deadzone)).clamp(0.0, 1.0),
+            false => f64::from(u8::from(magnitude > deadzone)),
+        };
+
+        let expo = self.expo.clamp(0.0, 1.0);
+        let curved = (1.0 - expo) * scaled + expo * scaled.powi(3);
+
+        let mut output = curved.copysign(normalized);
+
+        if self.invert {
+            output = -output;
+        }
+
+        AXIS_MIN + (output + 1.0) / 2.0 * (AXIS_MAX - AXIS_MIN)
+    }
+}
+
+// Values seen while the app captures a calibration, the first sample is taken as the resting center
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AxisCapture {
+    pub min: f64,
+    pub max: f64,
+    pub center: f64,
+}
+
+impl AxisCapture {
+    pub fn new(value: f64) -> Self {
+        AxisCapture {
+            min: value,
+            max: value,
+            center: value,
+        }
+    }
+
+    pub fn record(&mut self, value: f64) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    pub fn apply_to(&self, calibration: &mut AxisCalibration) {
+        calibration.min = self.min;
+        calibration.max = self.max;
+        calibration.center = self.center;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_is_identity() {
+        let calibration = AxisCalibration::default();
+
+        for raw in [AXIS_MIN, 1_000.0, (AXIS_MIN + AXIS_MAX) / 2.0, AXIS_MAX] {
+            assert!((calibration.apply(raw) - raw).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_range_deadzone_and_invert() {
+        let center = (AXIS_MIN + AXIS_MAX) / 2.0;
+        let calibration = AxisCalibration {
+            min: 100.0,
+            max: 3_900.0,
+            center: 2_000.0,
+            deadzone: 0.1,
+            saturation: 0.9,
+            ..Default::default()
+        };
+
+        assert_eq!(calibration.apply(2_050.0), center);
+        assert_eq!(calibration.apply(3_800.0), AXIS_MAX);
+        assert_eq!(calibration.apply(4_095.0), AXIS_MAX);
+        assert_eq!(calibration.apply(0.0), AXIS_MIN);
+
+        let inverted = AxisCalibration {
+            invert: true,
+            ..calibration
+        };
+
+        assert_eq!(inverted.apply(4_095.0), AXIS_MIN);
+    }
+
+    #[test]
+    fn test_expo() {
+        let calibration = AxisCalibration {
+            min: -1.0,
+            max: 1.0,
+            center: 0.0,
+            expo: 1.0,
+            ..Default::default()
+        };
+
+        let half = calibration.apply(0.5);
+        let expected = AXIS_MIN + (0.125 + 1.0) / 2.0 * (AXIS_MAX - AXIS_MIN);
+
+        assert!((half - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_capture() {
+        let mut capture = AxisCapture::new(2_000.0);
+
+        [1_800.0, 120.0, 3_950.0, 2_010.0]
+            .into_iter()
+            .for_each(|value| capture.record(value));
+
+        let mut calibration = AxisCalibration::default();
+        capture.apply_to(&mut calibration);
+
+        assert_eq!((calibration.min, calibration.max), (120.0, 3_950.0));
+        assert_eq!(calibration.center, 2_000.0);
+    }
+
+    #[test]
+    fn test_serde_name() {
+        #[derive(Debug, Serialize)]
+        enum Direction {
+            #[serde(rename = "Up")]
+            North,
+            Tilted(u8),
+        }
+
+        // The json name, not the Debug output
+        assert_eq!(serde_name(&Direction::North), "Up");
+        assert_eq!(serde_name(&Direction::Tilted(3)), r#"{"Tilted":3}"#);
+    }
+}
@@ -0,0 +1,956 @@
+use crate::event::EventSender;
+use crate::policy::{CommandGuard, Decision};
+use jojo_common::command::CustomCommand;
+use jojo_common::device::DeviceId;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex, Once, Weak};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+// Bytes kept from the end of stdout and stderr when a command is waited on
+const OUTPUT_TAIL_BYTES: usize = 4_096;
+// How long the output is read after the child is gone, a grandchild can hold the pipes open for longer
+const OUTPUT_GRACE_MILLIS: u64 = 200;
+const POLL_MILLIS: u64 = 10;
+const REAP_MILLIS: u64 = 500;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CommandMode {
+    // Spawn the program and return right away, output is discarded
+    #[default]
+    Detached,
+    // Wait until the program exits (or times out) and capture its output
+    Wait,
+}
+
+// What to do when the program launched by the same action is still running, only detached commands are tracked
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Lifecycle {
+    // Always spawn a new process
+    #[default]
+    Launch,
+    // Focus the running process if the backend can do it, otherwise leave it alone
+    LaunchOrFocus,
+    // Kill the running process, launch it otherwise
+    Toggle,
+    // Never spawn a second process
+    SingleInstance,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandSpec {
+    pub program: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub cwd: Option<PathBuf>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    #[serde(default)]
+    pub timeout_millis: Option<u64>,
+    #[serde(default)]
+    pub mode: CommandMode,
+    #[serde(default)]
+    pub lifecycle: Lifecycle,
+}
+
+impl CommandSpec {
+    pub fn new(program: impl Into<String>) -> Self {
+        CommandSpec {
+            program: program.into(),
+            ..Default::default()
+        }
+    }
+
+    // `CustomCommand::Binary` only carries a string, so a full spec travels as a json object inside it.
+    // Anything else is treated as a plain path to keep old mappings working.
+    pub fn parse(raw: &str) -> Self {
+        let trimmed = raw.trim();
+
+        if trimmed.starts_with('{') {
+            match serde_json::from_str::<CommandSpec>(trimmed) {
+                Ok(spec) => return spec,
+                Err(err) => warn!(
+                    "[command_spec]: invalid json spec, using it as a path: {}",
+                    err
+                ),
+            }
+        }
+
+        CommandSpec::new(raw)
+    }
+
+    // Identifies the action that launched a process, the same program with other args is another action
+    pub fn action_key(&self) -> String {
+        std::iter::once(self.program.as_str())
+            .chain(self.args.iter().map(String::as_str))
+            .collect::<Vec<&str>>()
+            .join("\u{1f}")
+    }
+
+    // Same spec with the absolute path of the executable Command would start. Bare names are looked up in the
+    // PATH of the spec env first, relative paths start from its cwd.
+    pub fn resolved(&self) -> std::io::Result<CommandSpec> {
+        let program = Path::new(&self.program);
+
+        let path = match program.components().count() > 1 || program.is_absolute() {
+            true => match &self.cwd {
+                Some(cwd) if program.is_relative() => cwd.join(program),
+                _ => program.to_path_buf(),
+            },
+            false => {
+                let paths = self
+                    .env
+                    .get("PATH")
+                    .map(OsString::from)
+                    .or_else(|| std::env::var_os("PATH"))
+                    .unwrap_or_default();
+
+                std::env::split_paths(&paths)
+                    .flat_map(|dir| {
+                        let path = dir.join(program);
+
+                        // Windows finds program.exe for program
+                        match cfg!(windows) && path.extension().is_none() {
+                            true => vec![path.with_extension("exe"), path],
+                            false => vec![path],
+                        }
+                    })
+                    .find(|path| path.is_file())
+                    .ok_or_else(|| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::NotFound,
+                            format!("{} is not in PATH", self.program),
+                        )
+                    })?
+            }
+        };
+
+        Ok(CommandSpec {
+            program: path.canonicalize()?.to_string_lossy().into_owned(),
+            ..self.clone()
+        })
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout_millis.map(Duration::from_millis)
+    }
+
+    fn to_command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+
+        command.args(&self.args).envs(&self.env);
+
+        if let Some(cwd) = &self.cwd {
+            command.current_dir(cwd);
+        }
+
+        command
+    }
+}
+
+impl From<CustomCommand> for CommandSpec {
+    fn from(command: CustomCommand) -> Self {
+        match command {
+            CustomCommand::Binary(path) => CommandSpec::parse(&path),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CommandStatus {
+    Spawned,
+    AlreadyRunning,
+    Focused,
+    Killed,
+    Exited(Option<i32>),
+    TimedOut,
+    Denied(String),
+    Failed(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandReport {
+    pub program: String,
+    pub pid: Option<u32>,
+    pub status: CommandStatus,
+    pub stdout_tail: String,
+    pub stderr_tail: String,
+    pub elapsed_millis: u64,
+}
+
+impl CommandReport {
+    pub fn new(spec: &CommandSpec, status: CommandStatus) -> Self {
+        CommandReport {
+            program: spec.program.clone(),
+            pid: None,
+            status,
+            stdout_tail: String::new(),
+            stderr_tail: String::new(),
+            elapsed_millis: 0,
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        matches!(
+            self.status,
+            CommandStatus::Spawned
+                | CommandStatus::AlreadyRunning
+                | CommandStatus::Focused
+                | CommandStatus::Killed
+                | CommandStatus::Exited(Some(0))
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunningCommand {
+    pub device_id: DeviceId,
+    pub program: String,
+    pub args: Vec<String>,
+    pub pid: u32,
+}
+
+// Blocking by design, callers run it inside spawn_blocking like the rest of the drivers
+pub trait CommandBackend: Send + Sync {
+    fn run(&self, spec: &CommandSpec) -> CommandReport;
+    fn is_running(&self, pid: u32) -> bool;
+    fn kill(&self, pid: u32) -> std::io::Result<()>;
+
+    // Bring the process window to the front, backends that can't do it return false
+    fn focus(&self, _pid: u32) -> bool {
+        false
+    }
+
+    // The guard checks the executable returned here, backends must run that one
+    fn resolve(&self, spec: &CommandSpec) -> std::io::Result<CommandSpec> {
+        spec.resolved()
+    }
+}
+
+// Every command coming from a device goes through the guard before reaching the backend
+#[derive(Clone)]
+pub struct CommandRunner {
+    backend: Arc<dyn CommandBackend>,
+    guard: CommandGuard,
+    // Detached processes launched by each action, Launch can start more than one
+    launched: Arc<Mutex<HashMap<String, Vec<RunningCommand>>>>,
+    cleanup_on_shutdown: bool,
+}
+
+impl CommandRunner {
+    pub fn new(backend: Arc<dyn CommandBackend>, guard: CommandGuard) -> Self {
+        CommandRunner {
+            backend,
+            guard,
+            launched: Arc::new(Mutex::new(HashMap::new())),
+            cleanup_on_shutdown: false,
+        }
+    }
+
+    pub fn with_cleanup_on_shutdown(mut self, cleanup_on_shutdown: bool) -> Self {
+        self.cleanup_on_shutdown = cleanup_on_shutdown;
+        self
+    }
+
+    pub fn guard(&self) -> &CommandGuard {
+        &self.guard
+    }
+
+    pub fn running(&self) -> Vec<RunningCommand> {
+        let mut launched = self.launched.lock().unwrap();
+
+        launched.retain(|_, instances| {
+            instances.retain(|running| self.backend.is_running(running.pid));
+            !instances.is_empty()
+        });
+        launched.values().flatten().cloned().collect()
+    }
+
+    // Kills the tracked processes if the runner was built with cleanup_on_shutdown
+    pub fn shutdown(&self) {
+        if !self.cleanup_on_shutdown {
+            return;
+        }
+
+        for running in self
+            .launched
+            .lock()
+            .unwrap()
+            .drain()
+            .flat_map(|(_, instances)| instances)
+        {
+            info!(
+                "[command_runner]: killing {} ({}) on shutdown",
+                running.program, running.pid
+            );
+
+            self.backend.kill(running.pid).unwrap_or_else(|err| {
+                error!("[command_runner]: cannot kill {}: {}", running.pid, err)
+            });
+        }
+    }
+
+    // Blocking, same as CommandBackend::run
+    pub fn run(
+        &self,
+        device_id: DeviceId,
+        spec: &CommandSpec,
+        server_event_tx: &EventSender,
+    ) -> CommandReport {
+        let spec = match self.backend.resolve(spec) {
+            Ok(spec) => spec,
+            Err(err) => return CommandReport::new(spec, CommandStatus::Failed(err.to_string())),
+        };
+        let spec = &spec;
+
+        if let Decision::Deny(reason) = self.guard.authorize(device_id, spec, server_event_tx) {
+            return CommandReport::new(spec, CommandStatus::Denied(reason.to_string()));
+        }
+
+        if spec.mode == CommandMode::Wait {
+            return self.backend.run(spec);
+        }
+
+        let key = spec.action_key();
+        // Held until the new process is tracked, two presses of the same action can't both launch it
+        let mut launched = self.launched.lock().unwrap();
+        let instances = launched.entry(key).or_default();
+
+        instances.retain(|running| self.backend.is_running(running.pid));
+
+        // The latest instance is the one focused or reported
+        if let Some(pid) = instances.last().map(|running| running.pid) {
+            let status = match spec.lifecycle {
+                Lifecycle::Launch => None,
+                Lifecycle::LaunchOrFocus if self.backend.focus(pid) => Some(CommandStatus::Focused),
+                Lifecycle::LaunchOrFocus | Lifecycle::SingleInstance => {
+                    Some(CommandStatus::AlreadyRunning)
+                }
+                Lifecycle::Toggle => {
+                    let mut status = CommandStatus::Killed;
+
+                    instances.retain(|running| match self.backend.kill(running.pid) {
+                        Ok(_) => false,
+                        Err(err) => {
+                            status = CommandStatus::Failed(err.to_string());
+                            true
+                        }
+                    });
+
+                    Some(status)
+                }
+            };
+
+            if let Some(status) = status {
+                info!("[command_runner]: {} ({}) {:?}", spec.program, pid, status);
+
+                let mut report = CommandReport::new(spec, status);
+                report.pid = Some(pid);
+
+                return report;
+            }
+        }
+
+        let report = self.backend.run(spec);
+
+        if let (CommandStatus::Spawned, Some(pid)) = (&report.status, report.pid) {
+            instances.push(RunningCommand {
+                device_id,
+                program: spec.program.clone(),
+                args: spec.args.clone(),
+                pid,
+            });
+        }
+
+        report
+    }
+}
+
+// Commands pass the guard but nothing is started, for replays with dry_run
+#[derive(Debug, Clone, Default)]
+pub struct DryRunBackend;
+
+impl CommandBackend for DryRunBackend {
+    fn run(&self, spec: &CommandSpec) -> CommandReport {
+        info!("[dry_run]: {:?}", spec);
+
+        CommandReport::new(spec, CommandStatus::Exited(Some(0)))
+    }
+
+    fn is_running(&self, _pid: u32) -> bool {
+        false
+    }
+
+    fn kill(&self, _pid: u32) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProcessBackend {
+    // Detached children are kept to query and kill them later, finished ones are reaped by a thread
+    children: Arc<Mutex<HashMap<u32, Child>>>,
+    reaper: Arc<Once>,
+}
+
+impl ProcessBackend {
+    // Started with the first detached child, it stops once the backend is dropped
+    fn start_reaper(&self) {
+        let children = Arc::downgrade(&self.children);
+
+        self.reaper.call_once(|| {
+            std::thread::spawn(move || reap(children));
+        });
+    }
+}
+
+fn reap(children: Weak<Mutex<HashMap<u32, Child>>>) {
+    loop {
+        std::thread::sleep(Duration::from_millis(REAP_MILLIS));
+
+        let Some(children) = children.upgrade() else {
+            break;
+        };
+
+        children
+            .lock()
+            .unwrap()
+            .retain(|_, child| matches!(child.try_wait(), Ok(None)));
+    }
+}
+
+impl CommandBackend for ProcessBackend {
+    fn run(&self, spec: &CommandSpec) -> CommandReport {
+        let start = Instant::now();
+
+        let mut report = match spec.mode {
+            CommandMode::Detached => self.run_detached(spec),
+            CommandMode::Wait => run_wait(spec),
+        };
+
+        report.elapsed_millis = start.elapsed().as_millis() as u64;
+
+        info!(
+            "[process_backend]: {} finished with {:?} in {}ms",
+            report.program, report.status, report.elapsed_millis
+        );
+
+        report
+    }
+
+    fn is_running(&self, pid: u32) -> bool {
+        let mut children = self.children.lock().unwrap();
+
+        match children.get_mut(&pid).map(|child| child.try_wait()) {
+            Some(Ok(None)) => true,
+            Some(_) => {
+                children.remove(&pid);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn focus(&self, pid: u32) -> bool {
+        // Only processes this backend launched, the pid of an exited one may belong to anything by now
+        if !self.is_running(pid) {
+            return false;
+        }
+
+        focus_window(pid)
+    }
+
+    fn kill(&self, pid: u32) -> std::io::Result<()> {
+        let child = self.children.lock().unwrap().remove(&pid);
+
+        match child {
+            Some(mut child) => {
+                child.kill()?;
+                child.wait().map(|_| ())
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("process {} was not launched by this backend", pid),
+            )),
+        }
+    }
+}
+
+impl ProcessBackend {
+    fn run_detached(&self, spec: &CommandSpec) -> CommandReport {
+        let child = spec
+            .to_command()
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+
+        match child {
+            Ok(child) => {
+                let mut report = CommandReport::new(spec, CommandStatus::Spawned);
+                report.pid = Some(child.id());
+
+                self.children.lock().unwrap().insert(child.id(), child);
+                self.start_reaper();
+
+                report
+            }
+            Err(err) => CommandReport::new(spec, CommandStatus::Failed(err.to_string())),
+        }
+    }
+}
+
+fn run_wait(spec: &CommandSpec) -> CommandReport {
+    let mut command = spec.to_command();
+
+    // Its own process group, so a timeout also kills whatever the command started
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+
+    let mut child = match command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(err) => return CommandReport::new(spec, CommandStatus::Failed(err.to_string())),
+    };
+
+    // Pipes are drained on their own threads, otherwise a chatty child blocks on a full pipe
+    let stdout = child.stdout.take().map(read_tail);
+    let stderr = child.stderr.take().map(read_tail);
+
+    let status = wait_with_timeout(&mut child, spec.timeout());
+    let deadline = Instant::now() + Duration::from_millis(OUTPUT_GRACE_MILLIS);
+
+    let mut report = CommandReport::new(spec, status);
+    report.pid = Some(child.id());
+    report.stdout_tail = stdout
+        .map(|tail| tail.collect(deadline))
+        .unwrap_or_default();
+    report.stderr_tail = stderr
+        .map(|tail| tail.collect(deadline))
+        .unwrap_or_default();
+
+    report
+}
+
+fn wait_with_timeout(child: &mut Child, timeout: Option<Duration>) -> CommandStatus {
+    let start = Instant::now();
+
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return CommandStatus::Exited(status.code()),
+            Ok(None) => {}
+            Err(err) => return CommandStatus::Failed(err.to_string()),
+        }
+
+        if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+            kill_tree(child)
+                .unwrap_or_else(|err| error!("[process_backend]: cannot kill child: {}", err));
+            child.wait().ok();
+
+            return CommandStatus::TimedOut;
+        }
+
+        std::thread::sleep(Duration::from_millis(POLL_MILLIS));
+    }
+}
+
+#[cfg(unix)]
+fn kill_tree(child: &mut Child) -> std::io::Result<()> {
+    // The child leads its group, see run_wait
+    match unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } {
+        0 => Ok(()),
+        _ => child.kill(),
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_tree(child: &mut Child) -> std::io::Result<()> {
+    child.kill()
+}
+
+struct Tail {
+    buffer: Arc<Mutex<Vec<u8>>>,
+    reader: JoinHandle<()>,
+}
+
+impl Tail {
+    // Whatever was read by the deadline, the reader thread is left behind if the pipe is still open
+    fn collect(self, deadline: Instant) -> String {
+        while !self.reader.is_finished() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(POLL_MILLIS));
+        }
+
+        let tail = self.buffer.lock().unwrap();
+
+        String::from_utf8_lossy(&tail).into_owned()
+    }
+}
+
+fn read_tail<R: Read + Send + 'static>(mut reader: R) -> Tail {
+    let buffer = Arc::new(Mutex::new(Vec::new()));
+    let tail = buffer.clone();
+
+    let reader = std::thread::spawn(move || {
+        let mut chunk = [0u8; 1_024];
+
+        while let Ok(read) = reader.read(&mut chunk) {
+            if read == 0 {
+                break;
+            }
+
+            let mut tail = tail.lock().unwrap();
+            tail.extend_from_slice(&chunk[..read]);
+
+            if tail.len() > OUTPUT_TAIL_BYTES {
+                let excess = tail.len() - OUTPUT_TAIL_BYTES;
+                tail.drain(..excess);
+            }
+        }
+    });
+
+    Tail { buffer, reader }
+}
+
+// First visible top level window of the process, restored if it's minimized
+#[cfg(windows)]
+fn focus_window(pid: u32) -> bool {
+    use windows_sys::Win32::Foundation::{BOOL, HWND, LPARAM};
+    use windows_sys::Win32::UI::WindowsAndMessaging::{
+        EnumWindows, GetWindowThreadProcessId, IsIconic, IsWindowVisible, SetForegroundWindow,
+        ShowWindow, SW_RESTORE,
+    };
+
+    struct Search {
+        pid: u32,
+        window: HWND,
+    }
+
+    unsafe extern "system" fn visit(window: HWND, search: LPARAM) -> BOOL {
+        let search = &mut *(search as *mut Search);
+        let mut pid = 0;
+
+        GetWindowThreadProcessId(window, &mut pid);
+
+        if pid == search.pid && IsWindowVisible(window) != 0 {
+            search.window = window;
+            // Stops the enumeration
+            return 0;
+        }
+
+        1
+    }
+
+    let mut search = Search { pid, window: 0 };
+
+    unsafe {
+        EnumWindows(Some(visit), &mut search as *mut Search as LPARAM);
+
+        if search.window == 0 {
+            return false;
+        }
+
+        if IsIconic(search.window) != 0 {
+            ShowWindow(search.window, SW_RESTORE);
+        }
+
+        SetForegroundWindow(search.window) != 0
+    }
+}
+
+// TODO: there is no single way to raise a window of another process on X11 and wayland
+#[cfg(not(windows))]
+fn focus_window(_pid: u32) -> bool {
+    false
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::event::ServerEvent;
+
+    #[test]
+    fn test_parse_spec() {
+        assert_eq!(
+            CommandSpec::parse("/usr/bin/spotify"),
+            CommandSpec::new("/usr/bin/spotify")
+        );
+
+        let spec = CommandSpec::parse(
+            r#"{"program": "sh", "args": ["-c", "exit 0"], "mode": "Wait", "timeout_millis": 500}"#,
+        );
+
+        assert_eq!(spec.program, "sh");
+        assert_eq!(spec.args, vec!["-c", "exit 0"]);
+        assert_eq!(spec.mode, CommandMode::Wait);
+        assert_eq!(spec.timeout_millis, Some(500));
+    }
+
+    #[test]
+    fn test_resolved() {
+        let sh = CommandSpec::new("sh").resolved().unwrap();
+
+        assert!(Path::new(&sh.program).is_absolute());
+        assert_eq!(
+            CommandSpec::new(&sh.program).resolved().unwrap().program,
+            sh.program
+        );
+
+        // The PATH of the spec is the one Command uses
+        let dir = std::env::temp_dir().join(format!("jojo-path-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("sh"), b"").unwrap();
+
+        let shadowed = CommandSpec {
+            env: HashMap::from([("PATH".to_string(), dir.to_string_lossy().into_owned())]),
+            ..CommandSpec::new("sh")
+        }
+        .resolved()
+        .unwrap();
+
+        assert_eq!(
+            Path::new(&shadowed.program),
+            dir.join("sh").canonicalize().unwrap()
+        );
+
+        let relative = CommandSpec {
+            cwd: Some(dir.clone()),
+            ..CommandSpec::new("./sh")
+        }
+        .resolved()
+        .unwrap();
+
+        assert_eq!(relative.program, shadowed.program);
+        assert!(CommandSpec::new("jojo-not-a-program").resolved().is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_process_backend_wait() {
+        let spec = CommandSpec {
+            program: "sh".to_string(),
+            args: vec![
+                "-c".to_string(),
+                "echo $JOJO; echo err 1>&2; exit 3".to_string(),
+            ],
+            env: HashMap::from([("JOJO".to_string(), "jojo".to_string())]),
+            mode: CommandMode::Wait,
+            ..Default::default()
+        };
+
+        let report = ProcessBackend::default().run(&spec);
+
+        assert_eq!(report.status, CommandStatus::Exited(Some(3)));
+        assert_eq!(report.stdout_tail, "jojo\n");
+        assert_eq!(report.stderr_tail, "err\n");
+    }
+
+    #[test]
+    fn test_process_backend_timeout() {
+        let spec = CommandSpec {
+            program: "sleep".to_string(),
+            args: vec!["5".to_string()],
+            timeout_millis: Some(100),
+            mode: CommandMode::Wait,
+            ..Default::default()
+        };
+
+        let report = ProcessBackend::default().run(&spec);
+
+        assert_eq!(report.status, CommandStatus::TimedOut);
+        assert!(report.elapsed_millis < 5_000);
+    }
+
+    #[test]
+    fn test_process_backend_grandchild() {
+        // The background sleep keeps the pipes open after the shell exits
+        let spec = CommandSpec {
+            program: "sh".to_string(),
+            args: vec!["-c".to_string(), "echo out; sleep 5 &".to_string()],
+            mode: CommandMode::Wait,
+            ..Default::default()
+        };
+
+        let report = ProcessBackend::default().run(&spec);
+
+        assert_eq!(report.status, CommandStatus::Exited(Some(0)));
+        assert_eq!(report.stdout_tail, "out\n");
+        assert!(report.elapsed_millis < 5_000);
+
+        let spec = CommandSpec {
+            args: vec!["-c".to_string(), "sleep 5 & sleep 5".to_string()],
+            timeout_millis: Some(100),
+            ..spec
+        };
+
+        let report = ProcessBackend::default().run(&spec);
+
+        assert_eq!(report.status, CommandStatus::TimedOut);
+        assert!(report.elapsed_millis < 5_000);
+    }
+
+    #[test]
+    fn test_process_backend_reaper() {
+        let backend = ProcessBackend::default();
+        let report = backend.run(&CommandSpec::new("true"));
+
+        assert_eq!(report.status, CommandStatus::Spawned);
+
+        std::thread::sleep(Duration::from_millis(REAP_MILLIS * 3));
+
+        assert!(backend.children.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_process_backend_missing_binary() {
+        let report = ProcessBackend::default().run(&CommandSpec::new("/definitely/not/here"));
+
+        assert!(matches!(report.status, CommandStatus::Failed(_)));
+    }
+
+    #[test]
+    fn test_runner_toggle() {
+        let device_id = DeviceId::new_v4();
+        let (tx, _rx) = tokio::sync::mpsc::channel::<ServerEvent>(32);
+        let tx = EventSender::from(tx);
+        let guard = CommandGuard::new(crate::policy::CommandPolicy {
+            unknown: crate::policy::UnknownCommand::Allow,
+            ..Default::default()
+        });
+        let runner = CommandRunner::new(Arc::new(ProcessBackend::default()), guard)
+            .with_cleanup_on_shutdown(true);
+
+        let spec = CommandSpec {
+            program: "sleep".to_string(),
+            args: vec!["5".to_string()],
+            lifecycle: Lifecycle::Toggle,
+            ..Default::default()
+        };
+
+        let launched = runner.run(device_id, &spec, &tx);
+        let running = runner.running();
+        let killed = runner.run(device_id, &spec, &tx);
+
+        assert_eq!(launched.status, CommandStatus::Spawned);
+        assert_eq!(running.len(), 1);
+        assert_eq!(running[0].pid, launched.pid.unwrap());
+        assert_eq!(killed.status, CommandStatus::Killed);
+        assert!(runner.running().is_empty());
+    }
+
+    #[test]
+    fn test_runner_launch_instances() {
+        let device_id = DeviceId::new_v4();
+        let (tx, _rx) = tokio::sync::mpsc::channel::<ServerEvent>(32);
+        let tx = EventSender::from(tx);
+        let guard = CommandGuard::new(crate::policy::CommandPolicy {
+            unknown: crate::policy::UnknownCommand::Allow,
+            ..Default::default()
+        });
+        let backend = Arc::new(ProcessBackend::default());
+        let runner = CommandRunner::new(backend.clone(), guard).with_cleanup_on_shutdown(true);
+
+        let spec = CommandSpec {
+            program: "sleep".to_string(),
+            args: vec!["5".to_string()],
+            lifecycle: Lifecycle::Launch,
+            ..Default::default()
+        };
+
+        let first = runner.run(device_id, &spec, &tx);
+        let second = runner.run(device_id, &spec, &tx);
+
+        assert_ne!(first.pid, second.pid);
+        assert_eq!(runner.running().len(), 2);
+
+        // Every instance is killed, not only the last one
+        runner.shutdown();
+
+        assert!(!backend.is_running(first.pid.unwrap()));
+        assert!(!backend.is_running(second.pid.unwrap()));
+    }
+
+    #[test]
+    fn test_runner_single_instance() {
+        let device_id = DeviceId::new_v4();
+        let (tx, _rx) = tokio::sync::mpsc::channel::<ServerEvent>(32);
+        let tx = EventSender::from(tx);
+        let guard = CommandGuard::new(crate::policy::CommandPolicy {
+            unknown: crate::policy::UnknownCommand::Allow,
+            ..Default::default()
+        });
+        let runner = CommandRunner::new(Arc::new(ProcessBackend::default()), guard)
+            .with_cleanup_on_shutdown(true);
+
+        let spec = CommandSpec {
+            program: "sleep".to_string(),
+            args: vec!["5".to_string()],
+            lifecycle: Lifecycle::SingleInstance,
+            ..Default::default()
+        };
+
+        let first = runner.run(device_id, &spec, &tx);
+        let second = runner.run(device_id, &spec, &tx);
+
+        runner.shutdown();
+
+        assert_eq!(first.status, CommandStatus::Spawned);
+        assert_eq!(second.status, CommandStatus::AlreadyRunning);
+        assert_eq!(second.pid, first.pid);
+        assert!(runner.running().is_empty());
+    }
+
+    #[test]
+    fn test_runner_concurrent_single_instance() {
+        let device_id = DeviceId::new_v4();
+        let (tx, _rx) = tokio::sync::mpsc::channel::<ServerEvent>(32);
+        let tx = EventSender::from(tx);
+        let guard = CommandGuard::new(crate::policy::CommandPolicy {
+            unknown: crate::policy::UnknownCommand::Allow,
+            ..Default::default()
+        });
+        let runner = CommandRunner::new(Arc::new(ProcessBackend::default()), guard)
+            .with_cleanup_on_shutdown(true);
+
+        let spec = CommandSpec {
+            program: "sleep".to_string(),
+            args: vec!["5".to_string()],
+            lifecycle: Lifecycle::SingleInstance,
+            ..Default::default()
+        };
+
+        // The same action pressed from a few devices at once
+        let reports: Vec<CommandReport> = std::thread::scope(|scope| {
+            let runs: Vec<_> = (0..4)
+                .map(|_| scope.spawn(|| runner.run(device_id, &spec, &tx)))
+                .collect();
+
+            runs.into_iter().map(|run| run.join().unwrap()).collect()
+        });
+
+        runner.shutdown();
+
+        assert_eq!(
+            reports
+                .iter()
+                .filter(|report| report.status == CommandStatus::Spawned)
+                .count(),
+            1
+        );
+        assert!(reports.iter().all(|report| matches!(
+            report.status,
+            CommandStatus::Spawned | CommandStatus::AlreadyRunning
+        )));
+    }
+}
@@ -0,0 +1,37 @@
+use crate::access::AccessConfig;
+use crate::limit::Limits;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+const TIMEOUT_MILLIS: u64 = 10_000;
+const PING_MILLIS: u64 = 5_000;
+
+// Connection settings, the defaults are the ones devices were built against
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    // A connection without a pong for this long is closed
+    pub timeout_millis: u64,
+    pub ping_millis: u64,
+    // Frames and messages a device can send
+    pub limits: Limits,
+    // Who can open a device socket and how many at once
+    pub access: AccessConfig,
+    // UpdateFirmware only reads images from here, without it firmware updates are off
+    pub firmware_dir: Option<PathBuf>,
+    // Drivers only log what they would do, replays use it to check a recording without a device taking over
+    pub dry_run: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            timeout_millis: TIMEOUT_MILLIS,
+            ping_millis: PING_MILLIS,
+            limits: Limits::default(),
+            access: AccessConfig::default(),
+            firmware_dir: None,
+            dry_run: false,
+        }
+    }
+}
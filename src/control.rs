@@ -0,0 +1,266 @@
+use crate::command::RunningCommand;
+use crate::dispatch::{Delivery, Target, Undelivered};
+use crate::event::{ControlEvent, ServerEvent};
+use crate::ota::{FirmwareImage, FirmwareProgress};
+use crate::protocol::{Capability, ExtServerMessage};
+use crate::registry::ButtonId;
+use crate::telemetry::TelemetrySample;
+use crate::AppState;
+use axum::extract::ws::{Message, WebSocket};
+use futures_util::stream::SplitSink;
+use futures_util::{SinkExt, StreamExt};
+use jojo_common::button::ButtonAction;
+use jojo_common::device::{Device, DeviceId};
+use jojo_common::message::ServerMessage;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+
+// Commands a /control client sends as json text frames, they do what the app does through the channels
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ControlCommand {
+    UpdateDevice(Target, HashMap<ButtonId, Vec<ButtonAction>>),
+    RestartDevice(Target),
+    ClearCredentials(Target),
+    // Replaces the devices of a group, an empty list removes it
+    SetGroup(String, Vec<DeviceId>),
+    // Image in ServerConfig::firmware_dir, the version defaults to its file name
+    UpdateFirmware {
+        target: Target,
+        path: PathBuf,
+        version: Option<String>,
+    },
+    CancelFirmware(DeviceId),
+    ListDevices,
+    // Devices with what the dashboard shows next to them
+    Status,
+    // History of a device, oldest sample first
+    Telemetry(DeviceId),
+    // Answer to a ServerEvent::CommandConfirmation
+    Confirm(Uuid, bool),
+    // Detached processes the actions launched that are still alive
+    RunningCommands,
+}
+
+// Replies only go to the client that sent the command
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ControlReply {
+    Event(ControlEvent),
+    // Devices the message was handed to and the ones it didn't reach
+    Delivery(Delivery),
+    Done,
+    Devices(Vec<Device>),
+    Status(Vec<DeviceStatus>),
+    Telemetry(DeviceId, Vec<TelemetrySample>),
+    Confirmed(Uuid, bool),
+    RunningCommands(Vec<RunningCommand>),
+    Error(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceStatus {
+    pub device_id: DeviceId,
+    pub device: Device,
+    // False while the device is in the room without a socket, like during a replay
+    pub connected: bool,
+    // None until the first pong that echoes a ping
+    pub rtt_millis: Option<f64>,
+    pub gamepad_slot: Option<u8>,
+    pub telemetry: Option<TelemetrySample>,
+}
+
+pub async fn control_handler(ws: WebSocket, state: AppState) {
+    let (mut tx, mut rx) = ws.split();
+    let mut events_rx = state.server_event_tx.subscribe();
+
+    info!("[control]: client connected");
+
+    loop {
+        let reply = tokio::select! {
+            event = events_rx.recv() => match event {
+                Ok(event) => ControlReply::Event(event),
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("[control]: lagged, {} events skipped", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            },
+            message = rx.next() => match message {
+                Some(Ok(Message::Text(text))) => match serde_json::from_str(&text) {
+                    Ok(command) => command_handler(command, &state).await,
+                    Err(err) => ControlReply::Error(format!("bad command: {}", err)),
+                },
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+        };
+
+        if !send_reply(&mut tx, &reply).await {
+            break;
+        }
+    }
+
+    info!("[control]: client disconnected");
+}
+
+async fn command_handler(command: ControlCommand, state: &AppState) -> ControlReply {
+    info!("[control]: {:?}", command);
+
+    match command {
+        ControlCommand::UpdateDevice(target, buttons) => {
+            // Like the app messages, mappings are kept for the devices that are offline too
+            let mut registry = state.registry.write().await;
+
+            for device_id in state.dispatcher.resolve(&target) {
+                registry.set_buttons(device_id, buttons.clone());
+            }
+            drop(registry);
+
+            ControlReply::Delivery(state.dispatcher.send_to(&target, |device_id| {
+                ServerMessage::UpdateDevice(device_id, buttons.clone())
+            }))
+        }
+        ControlCommand::RestartDevice(target) => ControlReply::Delivery(
+            state
+                .dispatcher
+                .send_to(&target, ServerMessage::RestartDevice),
+        ),
+        ControlCommand::ClearCredentials(target) => ControlReply::Delivery(
+            state
+                .dispatcher
+                .send_to(&target, ServerMessage::ClearCredentials),
+        ),
+        ControlCommand::SetGroup(name, devices) => {
+            if devices.is_empty() {
+                state.dispatcher.remove_group(&name);
+            } else {
+                state.dispatcher.set_group(name, devices);
+            }
+
+            ControlReply::Done
+        }
+        ControlCommand::UpdateFirmware {
+            target,
+            path,
+            version,
+        } => {
+            let Some(dir) = state.config.firmware_dir.as_deref() else {
+                return ControlReply::Error("no firmware directory is configured".to_string());
+            };
+
+            let image = match FirmwareImage::load(dir, &path, version).await {
+                Ok(image) => Arc::new(image),
+                Err(err) => return ControlReply::Error(format!("bad firmware image: {}", err)),
+            };
+
+            let mut delivery = Delivery::default();
+
+            // Offline devices get the offer when they reconnect, connected ones have to speak firmware
+            for device_id in state.dispatcher.resolve(&target) {
+                let unsupported = state
+                    .dispatcher
+                    .peer(&device_id)
+                    .is_some_and(|peer| !peer.capabilities.contains(&Capability::Firmware));
+
+                if unsupported {
+                    delivery
+                        .undelivered
+                        .push((device_id, Undelivered::Unsupported));
+                    continue;
+                }
+
+                let offer = state.firmware.start(device_id, image.clone());
+
+                match state.dispatcher.send(device_id, offer) {
+                    Ok(()) => delivery.delivered.push(device_id),
+                    Err(undelivered) => delivery.undelivered.push((device_id, undelivered)),
+                }
+            }
+
+            for device_id in delivery.delivered.iter() {
+                let Some(ExtServerMessage::FirmwareOffer { update_id, .. }) =
+                    state.firmware.offer(device_id)
+                else {
+                    continue;
+                };
+                let progress = FirmwareProgress::Offered {
+                    update_id,
+                    version: image.version.clone(),
+                };
+
+                state
+                    .server_event_tx
+                    .send(ServerEvent::Firmware(*device_id, progress))
+                    .await
+                    .unwrap_or_else(|_| info!("[control]: server_event_tx send error"));
+            }
+
+            ControlReply::Delivery(delivery)
+        }
+        ControlCommand::CancelFirmware(device_id) => match state.firmware.cancel(&device_id) {
+            true => ControlReply::Done,
+            false => ControlReply::Error(format!("no firmware update for {}", device_id)),
+        },
+        ControlCommand::ListDevices => {
+            let devices = state.devices.read().await;
+
+            ControlReply::Devices(
+                devices
+                    .keys()
+                    .filter_map(|device_id| devices.get(device_id).cloned())
+                    .collect(),
+            )
+        }
+        ControlCommand::Status => {
+            let devices = state.devices.read().await;
+            let connected = state.dispatcher.connected();
+            let slots = state.gamepads.assignments();
+
+            ControlReply::Status(
+                devices
+                    .keys()
+                    .filter_map(|device_id| {
+                        Some(DeviceStatus {
+                            device_id: *device_id,
+                            device: devices.get(device_id)?.clone(),
+                            connected: connected.contains(device_id),
+                            rtt_millis: state
+                                .metrics
+                                .rtt(device_id)
+                                .map(|rtt| rtt.as_secs_f64() * 1_000.0),
+                            gamepad_slot: slots.get(device_id).copied(),
+                            telemetry: state.telemetry.latest(device_id),
+                        })
+                    })
+                    .collect(),
+            )
+        }
+        ControlCommand::Telemetry(device_id) => {
+            ControlReply::Telemetry(device_id, state.telemetry.history(&device_id))
+        }
+        ControlCommand::Confirm(request_id, allow) => {
+            let confirmed = state.command_runner.guard().confirm(request_id, allow);
+
+            ControlReply::Confirmed(request_id, confirmed)
+        }
+        ControlCommand::RunningCommands => {
+            ControlReply::RunningCommands(state.command_runner.running())
+        }
+    }
+}
+
+async fn send_reply(tx: &mut SplitSink<WebSocket, Message>, reply: &ControlReply) -> bool {
+    let text = serde_json::to_string(reply).expect("[control]: cannot serialize");
+
+    match tx.send(Message::Text(text)).await {
+        Ok(_) => true,
+        Err(err) => {
+            error!("[control]: cannot send reply, err: {}", err);
+            false
+        }
+    }
+}
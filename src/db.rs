@@ -68,15 +68,20 @@ impl DeviceMap {
     ) -> Option<jojo_common::device::Device> {
         info!("[DeviceMap]: insert key: {:?}, value: {:?}", key, value);
 
-        sender
-            .send(jojo_common::room::RoomEvent::new(
-                key,
-                jojo_common::room::RoomAction::Join,
-            ))
-            .await
-            .unwrap();
+        let previous = self.custom_map.insert(key, value);
+
+        // A device sent again by its session is an update, not a new join
+        if previous.is_none() {
+            sender
+                .send(jojo_common::room::RoomEvent::new(
+                    key,
+                    jojo_common::room::RoomAction::Join,
+                ))
+                .await
+                .unwrap();
+        }
 
-        self.custom_map.insert(key, value)
+        previous
     }
 
     pub fn keys(&self) -> Keys<'_, jojo_common::device::DeviceId, jojo_common::device::Device> {
@@ -140,6 +145,34 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_device_map_update() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<jojo_common::room::RoomEvent>(32);
+        let key = jojo_common::device::DeviceId::new_v4();
+
+        let mut device_map = DeviceMap::new();
+
+        let first = device_map
+            .insert(key, jojo_common::device::Device::default(), tx.clone())
+            .await;
+        let second = device_map
+            .insert(key, jojo_common::device::Device::default(), tx.clone())
+            .await;
+
+        device_map.remove(&key, tx).await;
+
+        assert!(first.is_none());
+        assert!(second.is_some());
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            jojo_common::room::RoomEvent::new(key, jojo_common::room::RoomAction::Join)
+        );
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            jojo_common::room::RoomEvent::new(key, jojo_common::room::RoomAction::Leave)
+        );
+    }
+
     #[tokio::test]
     async fn test_device_map_thread_safe() {
         let (tx, mut rx) = tokio::sync::mpsc::channel::<jojo_common::room::RoomEvent>(32);
@@ -0,0 +1,300 @@
+use crate::protocol::{Outbound, Peer};
+use jojo_common::device::DeviceId;
+use jojo_common::message::ServerMessage;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::watch;
+
+// Messages queued for a session before new ones are reported as undelivered
+const SESSION_CAPACITY: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Target {
+    Device(DeviceId),
+    Group(String),
+    All,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Undelivered {
+    NotConnected,
+    // The session isn't keeping up with its socket
+    Full,
+    // The client didn't negotiate the capability the message needs
+    Unsupported,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Delivery {
+    pub delivered: Vec<DeviceId>,
+    pub undelivered: Vec<(DeviceId, Undelivered)>,
+}
+
+#[derive(Debug)]
+struct SessionSender {
+    // Tells a session from the one that replaced it when a device reconnects
+    generation: u64,
+    tx: mpsc::Sender<Outbound>,
+    // Follows the hello of the client
+    peer: watch::Receiver<Peer>,
+}
+
+#[derive(Debug, Default)]
+struct Sessions {
+    senders: HashMap<DeviceId, SessionSender>,
+    groups: HashMap<String, HashSet<DeviceId>>,
+    generation: u64,
+}
+
+// Delivers server messages straight to the session of each device
+#[derive(Debug, Clone, Default)]
+pub struct Dispatcher {
+    sessions: Arc<RwLock<Sessions>>,
+}
+
+impl Dispatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // A new session of the same device takes over, the old one stops receiving
+    pub fn register(
+        &self,
+        device_id: DeviceId,
+        peer: watch::Receiver<Peer>,
+    ) -> (u64, mpsc::Receiver<Outbound>) {
+        let (tx, rx) = mpsc::channel(SESSION_CAPACITY);
+        let mut sessions = self.sessions.write().unwrap();
+
+        sessions.generation += 1;
+        let generation = sessions.generation;
+
+        sessions.senders.insert(
+            device_id,
+            SessionSender {
+                generation,
+                tx,
+                peer,
+            },
+        );
+
+        (generation, rx)
+    }
+
+    pub fn unregister(&self, device_id: &DeviceId, generation: u64) {
+        let mut sessions = self.sessions.write().unwrap();
+
+        if sessions
+            .senders
+            .get(device_id)
+            .is_some_and(|sender| sender.generation == generation)
+        {
+            sessions.senders.remove(device_id);
+        }
+    }
+
+    pub fn set_group(&self, name: impl Into<String>, devices: impl IntoIterator<Item = DeviceId>) {
+        self.sessions
+            .write()
+            .unwrap()
+            .groups
+            .insert(name.into(), devices.into_iter().collect());
+    }
+
+    pub fn remove_group(&self, name: &str) -> bool {
+        self.sessions.write().unwrap().groups.remove(name).is_some()
+    }
+
+    // What the connected session of the device negotiated
+    pub fn peer(&self, device_id: &DeviceId) -> Option<Peer> {
+        self.sessions
+            .read()
+            .unwrap()
+            .senders
+            .get(device_id)
+            .map(|sender| sender.peer.borrow().clone())
+    }
+
+    pub fn connected(&self) -> Vec<DeviceId> {
+        self.sessions
+            .read()
+            .unwrap()
+            .senders
+            .keys()
+            .copied()
+            .collect()
+    }
+
+    // Devices a target names, groups may hold devices that aren't connected
+    pub fn resolve(&self, target: &Target) -> Vec<DeviceId> {
+        let sessions = self.sessions.read().unwrap();
+
+        match target {
+            Target::Device(device_id) => vec![*device_id],
+            Target::Group(name) => match sessions.groups.get(name) {
+                Some(devices) => devices.iter().copied().collect(),
+                None => {
+                    warn!("[dispatcher]: group {} doesn't exist", name);
+                    Vec::new()
+                }
+            },
+            Target::All => sessions.senders.keys().copied().collect(),
+        }
+    }
+
+    pub fn send(
+        &self,
+        device_id: DeviceId,
+        message: impl Into<Outbound>,
+    ) -> Result<(), Undelivered> {
+        let sessions = self.sessions.read().unwrap();
+
+        let message = message.into();
+
+        let result = match sessions.senders.get(&device_id) {
+            Some(sender) if !sender.peer.borrow().accepts(&message) => {
+                Err(Undelivered::Unsupported)
+            }
+            Some(sender) => sender.tx.try_send(message).map_err(|err| match err {
+                TrySendError::Full(_) => Undelivered::Full,
+                TrySendError::Closed(_) => Undelivered::NotConnected,
+            }),
+            None => Err(Undelivered::NotConnected),
+        };
+
+        if let Err(undelivered) = result {
+            warn!(
+                "[dispatcher]: message for {} undelivered: {:?}",
+                device_id, undelivered
+            );
+        }
+
+        result
+    }
+
+    // The message is built for each device, since server messages carry the id of their device
+    pub fn send_to<M: Into<Outbound>>(
+        &self,
+        target: &Target,
+        message: impl Fn(DeviceId) -> M,
+    ) -> Delivery {
+        let mut delivery = Delivery::default();
+
+        for device_id in self.resolve(target) {
+            match self.send(device_id, message(device_id)) {
+                Ok(()) => delivery.delivered.push(device_id),
+                Err(undelivered) => delivery.undelivered.push((device_id, undelivered)),
+            }
+        }
+
+        delivery
+    }
+}
+
+// Device a message from the app is meant for
+#[allow(unreachable_patterns)]
+pub fn message_device_id(message: &ServerMessage) -> Option<DeviceId> {
+    match message {
+        ServerMessage::UpdateDevice(device_id, _)
+        | ServerMessage::RestartDevice(device_id)
+        | ServerMessage::ClearCredentials(device_id) => Some(*device_id),
+        // jojo_common may add messages that aren't for a single device
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Capability, ExtServerMessage};
+    use uuid::Uuid;
+
+    fn peer(capabilities: Vec<Capability>) -> watch::Receiver<Peer> {
+        watch::channel(Peer {
+            capabilities,
+            ..Peer::default()
+        })
+        .1
+    }
+
+    #[test]
+    fn test_targets() {
+        let (first, second, offline) = (DeviceId::new_v4(), DeviceId::new_v4(), DeviceId::new_v4());
+        let dispatcher = Dispatcher::new();
+
+        let (_, mut first_rx) = dispatcher.register(first, peer(Vec::new()));
+        let (_, mut second_rx) = dispatcher.register(second, peer(Vec::new()));
+
+        dispatcher.set_group("desk", [first, offline]);
+
+        let delivery = dispatcher.send_to(&Target::Group("desk".to_string()), |device_id| {
+            ServerMessage::RestartDevice(device_id)
+        });
+
+        assert_eq!(delivery.delivered, vec![first]);
+        assert_eq!(
+            delivery.undelivered,
+            vec![(offline, Undelivered::NotConnected)]
+        );
+        assert!(matches!(
+            first_rx.try_recv(),
+            Ok(Outbound::Server(ServerMessage::RestartDevice(device_id))) if device_id == first
+        ));
+        assert!(second_rx.try_recv().is_err());
+
+        let delivery = dispatcher.send_to(&Target::All, ServerMessage::RestartDevice);
+
+        assert_eq!(delivery.delivered.len(), 2);
+        assert!(second_rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let device_id = DeviceId::new_v4();
+        let dispatcher = Dispatcher::new();
+
+        let (old_generation, _old_rx) = dispatcher.register(device_id, peer(Vec::new()));
+        let (generation, mut rx) = dispatcher.register(device_id, peer(Vec::new()));
+
+        // The old session closing must not remove the new one
+        dispatcher.unregister(&device_id, old_generation);
+
+        assert!(dispatcher
+            .send(device_id, ServerMessage::RestartDevice(device_id))
+            .is_ok());
+        assert!(rx.try_recv().is_ok());
+
+        dispatcher.unregister(&device_id, generation);
+
+        assert_eq!(
+            dispatcher.send(device_id, ServerMessage::RestartDevice(device_id)),
+            Err(Undelivered::NotConnected)
+        );
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let (legacy, firmware) = (DeviceId::new_v4(), DeviceId::new_v4());
+        let dispatcher = Dispatcher::new();
+
+        let (_, mut legacy_rx) = dispatcher.register(legacy, peer(Vec::new()));
+        let (_, mut firmware_rx) = dispatcher.register(firmware, peer(vec![Capability::Firmware]));
+
+        // The writer would drop it, so it must not count as delivered
+        let delivery = dispatcher.send_to(&Target::All, |_| {
+            ExtServerMessage::FirmwareFinalize(Uuid::new_v4())
+        });
+
+        assert_eq!(delivery.delivered, vec![firmware]);
+        assert_eq!(
+            delivery.undelivered,
+            vec![(legacy, Undelivered::Unsupported)]
+        );
+        assert!(legacy_rx.try_recv().is_err());
+        assert!(firmware_rx.try_recv().is_ok());
+        assert!(dispatcher.peer(&legacy).unwrap().capabilities.is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+use crate::command::{CommandReport, CommandSpec};
+use crate::dispatch::Undelivered;
+use crate::limit::Violation;
+use crate::ota::FirmwareProgress;
+use crate::session::ActiveProfile;
+use crate::telemetry::TelemetryAlert;
+use jojo_common::device::{Device, DeviceId};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::{broadcast, mpsc};
+use uuid::Uuid;
+
+// Events a slow /control client can fall behind before it starts missing them
+const CONTROL_CAPACITY: usize = 64;
+
+// Events emitted by the server that don't fit in jojo_common::room::RoomEvent
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ServerEvent {
+    CommandReport(DeviceId, CommandReport),
+    // Answer it with CommandGuard::confirm(request_id, allow)
+    CommandConfirmation(Uuid, DeviceId, CommandSpec),
+    ProfileChanged(DeviceId, ActiveProfile),
+    // None when the device released its slot
+    GamepadSlot(DeviceId, Option<u8>),
+    // A message from the app that didn't reach its device
+    Undelivered(DeviceId, Undelivered),
+    Room(DeviceId, SessionInfo, RoomChange),
+    Telemetry(DeviceId, TelemetryAlert),
+    Firmware(DeviceId, FirmwareProgress),
+}
+
+// Identifies one websocket of a device, a reconnect gets a new session id
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub session_id: Uuid,
+    // None for sessions without a socket, like replays, or connected through the local socket
+    pub remote_addr: Option<SocketAddr>,
+}
+
+impl SessionInfo {
+    pub fn new(remote_addr: Option<SocketAddr>) -> Self {
+        SessionInfo {
+            session_id: Uuid::new_v4(),
+            remote_addr,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisconnectReason {
+    // The device sent a close frame
+    Closed,
+    // No pong within ServerConfig::timeout_millis
+    Timeout,
+    // The socket failed while sending to the device
+    SendError,
+    // The device connected again and the new session took over
+    Replaced,
+    // The hello couldn't be negotiated, the client got the reason in the close frame
+    Incompatible(String),
+    // Closed by the violation policy of the limits
+    Violation(Violation),
+    // The recording a replayed device came from is over
+    ReplayEnded,
+}
+
+// Richer twin of jojo_common::room::RoomEvent, which is still sent to the app
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RoomChange {
+    Joined(Device),
+    // The device was sent again by a session that had already joined
+    Updated(Device),
+    Left(DisconnectReason),
+}
+
+// Everything the /control endpoint streams, room events included since the app channel for them is an mpsc
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ControlEvent {
+    Joined(DeviceId),
+    Left(DeviceId),
+    Server(ServerEvent),
+}
+
+// Sends server events to the app and copies them to the /control subscribers, it has the same send methods as
+// the mpsc sender it wraps
+#[derive(Debug, Clone)]
+pub struct EventSender {
+    app_tx: mpsc::Sender<ServerEvent>,
+    control_tx: broadcast::Sender<ControlEvent>,
+}
+
+impl From<mpsc::Sender<ServerEvent>> for EventSender {
+    fn from(app_tx: mpsc::Sender<ServerEvent>) -> Self {
+        EventSender {
+            app_tx,
+            control_tx: broadcast::channel(CONTROL_CAPACITY).0,
+        }
+    }
+}
+
+impl EventSender {
+    pub async fn send(&self, event: ServerEvent) -> Result<(), SendError<ServerEvent>> {
+        self.publish(ControlEvent::Server(event.clone()));
+        self.app_tx.send(event).await
+    }
+
+    pub fn blocking_send(&self, event: ServerEvent) -> Result<(), SendError<ServerEvent>> {
+        self.publish(ControlEvent::Server(event.clone()));
+        self.app_tx.blocking_send(event)
+    }
+
+    // Nobody listening on /control is fine
+    pub fn publish(&self, event: ControlEvent) {
+        self.control_tx.send(event).ok();
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ControlEvent> {
+        self.control_tx.subscribe()
+    }
+
+    pub fn control_subscribers(&self) -> usize {
+        self.control_tx.receiver_count()
+    }
+}
@@ -0,0 +1,158 @@
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum Smoothing {
+    #[default]
+    None,
+    // Exponential moving average, alpha 1 keeps the raw value
+    Ema {
+        alpha: f64,
+    },
+    // https://gery.casiez.net/1euro/
+    OneEuro {
+        min_cutoff: f64,
+        beta: f64,
+        derivative_cutoff: f64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FilterConfig {
+    pub smoothing: Smoothing,
+    // Changes smaller than this many raw units are dropped
+    pub hysteresis: f64,
+}
+
+// Filter state of one axis, it lives in the device session
+#[derive(Debug, Clone, Default)]
+pub struct AxisFilter {
+    smoothed: Option<f64>,
+    derivative: f64,
+    last_sample: Option<Instant>,
+    last_output: Option<f64>,
+}
+
+impl AxisFilter {
+    // Returns None when the change is under the hysteresis threshold
+    pub fn filter(&mut self, config: &FilterConfig, value: f64, now: Instant) -> Option<f64> {
+        let smoothed = match (config.smoothing, self.smoothed) {
+            (Smoothing::None, _) | (_, None) => value,
+            (Smoothing::Ema { alpha }, Some(previous)) => {
+                lerp(previous, value, alpha.clamp(0.0, 1.0))
+            }
+            (
+                Smoothing::OneEuro {
+                    min_cutoff,
+                    beta,
+                    derivative_cutoff,
+                },
+                Some(previous),
+            ) => {
+                let elapsed = self
+                    .last_sample
+                    .map(|last_sample| now.duration_since(last_sample).as_secs_f64())
+                    .filter(|elapsed| *elapsed > 0.0)
+                    .unwrap_or(f64::EPSILON);
+
+                let derivative = (value - previous) / elapsed;
+                self.derivative = lerp(
+                    self.derivative,
+                    derivative,
+                    smoothing_factor(derivative_cutoff, elapsed),
+                );
+
+                let cutoff = min_cutoff + beta * self.derivative.abs();
+
+                lerp(previous, value, smoothing_factor(cutoff, elapsed))
+            }
+        };
+
+        self.smoothed = Some(smoothed);
+        self.last_sample = Some(now);
+
+        match self.last_output {
+            Some(last_output) if (smoothed - last_output).abs() < config.hysteresis => None,
+            _ => {
+                self.last_output = Some(smoothed);
+                Some(smoothed)
+            }
+        }
+    }
+}
+
+fn lerp(from: f64, to: f64, alpha: f64) -> f64 {
+    from + alpha * (to - from)
+}
+
+fn smoothing_factor(cutoff: f64, elapsed: f64) -> f64 {
+    let tau = 1.0 / (2.0 * PI * cutoff.max(f64::EPSILON));
+
+    1.0 / (1.0 + tau / elapsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_ema() {
+        let config = FilterConfig {
+            smoothing: Smoothing::Ema { alpha: 0.5 },
+            ..Default::default()
+        };
+        let mut filter = AxisFilter::default();
+        let now = Instant::now();
+
+        assert_eq!(filter.filter(&config, 100.0, now), Some(100.0));
+        assert_eq!(filter.filter(&config, 200.0, now), Some(150.0));
+        assert_eq!(filter.filter(&config, 200.0, now), Some(175.0));
+    }
+
+    #[test]
+    fn test_hysteresis() {
+        let config = FilterConfig {
+            hysteresis: 10.0,
+            ..Default::default()
+        };
+        let mut filter = AxisFilter::default();
+        let now = Instant::now();
+
+        assert_eq!(filter.filter(&config, 100.0, now), Some(100.0));
+        assert_eq!(filter.filter(&config, 105.0, now), None);
+        assert_eq!(filter.filter(&config, 95.0, now), None);
+        assert_eq!(filter.filter(&config, 111.0, now), Some(111.0));
+    }
+
+    #[test]
+    fn test_one_euro() {
+        let config = FilterConfig {
+            smoothing: Smoothing::OneEuro {
+                min_cutoff: 1.0,
+                beta: 0.0,
+                derivative_cutoff: 1.0,
+            },
+            ..Default::default()
+        };
+        let mut filter = AxisFilter::default();
+        let mut now = Instant::now();
+
+        assert_eq!(filter.filter(&config, 0.0, now), Some(0.0));
+
+        // Jitter is damped, a sustained change is followed
+        now += Duration::from_millis(10);
+        let jitter = filter.filter(&config, 100.0, now).unwrap();
+
+        let mut settled = jitter;
+        for _ in 0..500 {
+            now += Duration::from_millis(10);
+            settled = filter.filter(&config, 100.0, now).unwrap();
+        }
+
+        assert!(jitter < 10.0);
+        assert!(settled > 99.0);
+    }
+}
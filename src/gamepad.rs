@@ -0,0 +1,190 @@
+use jojo_common::device::DeviceId;
+use jojo_common::driver::gamepad::GamepadDriver;
+use log::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// vJoy supports up to 16 devices
+pub const GAMEPAD_SLOTS: u8 = 16;
+// jojo_common only knows how to acquire the first vJoy device, a second slot would be the same controller under
+// another number, so only one is handed out until it can build a driver by id
+pub const DRIVER_SLOTS: u8 = 1;
+
+pub type SharedGamepad = Arc<Mutex<GamepadDriver>>;
+pub type GamepadFactory = dyn Fn(u8) -> anyhow::Result<SharedGamepad> + Send + Sync;
+
+#[derive(Debug, Clone, Default)]
+pub struct SlotAllocator {
+    slots: u8,
+    in_use: HashMap<u8, DeviceId>,
+    // Last slot of each device, it gets it back on reconnect if nobody took it
+    last: HashMap<DeviceId, u8>,
+}
+
+impl SlotAllocator {
+    pub fn new(slots: u8) -> Self {
+        SlotAllocator {
+            slots,
+            ..Default::default()
+        }
+    }
+
+    // Slots start at 1 like vJoy ids, a pinned slot wins over the last one used
+    pub fn allocate(&mut self, device_id: DeviceId, pinned: Option<u8>) -> Option<u8> {
+        if let Some(slot) = self.slot(&device_id) {
+            return Some(slot);
+        }
+
+        let is_free =
+            |slot: &u8| (1..=self.slots).contains(slot) && !self.in_use.contains_key(slot);
+
+        if let Some(slot) = pinned.filter(|slot| !is_free(slot)) {
+            warn!(
+                "[slot_allocator]: pinned slot {} of {} is not available",
+                slot, device_id
+            );
+        }
+
+        let slot = pinned
+            .filter(is_free)
+            .or_else(|| self.last.get(&device_id).copied().filter(is_free))
+            .or_else(|| (1..=self.slots).find(is_free))?;
+
+        self.in_use.insert(slot, device_id);
+        self.last.insert(device_id, slot);
+
+        Some(slot)
+    }
+
+    pub fn release(&mut self, device_id: &DeviceId) -> Option<u8> {
+        let slot = self.slot(device_id)?;
+        self.in_use.remove(&slot);
+
+        Some(slot)
+    }
+
+    pub fn slot(&self, device_id: &DeviceId) -> Option<u8> {
+        self.in_use
+            .iter()
+            .find(|(_, owner)| *owner == device_id)
+            .map(|(slot, _)| *slot)
+    }
+
+    pub fn assignments(&self) -> HashMap<DeviceId, u8> {
+        self.in_use
+            .iter()
+            .map(|(slot, device_id)| (*device_id, *slot))
+            .collect()
+    }
+}
+
+// One virtual controller per device, drivers stay alive after a release so reconnecting doesn't acquire vJoy again
+#[derive(Clone)]
+pub struct GamepadPool {
+    allocator: Arc<Mutex<SlotAllocator>>,
+    drivers: Arc<Mutex<HashMap<u8, SharedGamepad>>>,
+    factory: Arc<GamepadFactory>,
+}
+
+impl Default for GamepadPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GamepadPool {
+    // TODO: one driver per vJoy id once jojo_common can build them, then this goes up to GAMEPAD_SLOTS
+    pub fn new() -> Self {
+        Self::with_factory(DRIVER_SLOTS, |_slot| {
+            Ok(Arc::new(Mutex::new(GamepadDriver::default())))
+        })
+    }
+
+    pub fn with_factory(
+        slots: u8,
+        factory: impl Fn(u8) -> anyhow::Result<SharedGamepad> + Send + Sync + 'static,
+    ) -> Self {
+        GamepadPool {
+            allocator: Arc::new(Mutex::new(SlotAllocator::new(slots))),
+            drivers: Arc::new(Mutex::new(HashMap::new())),
+            factory: Arc::new(factory),
+        }
+    }
+
+    // Blocking, creating a driver talks with the virtual joystick
+    pub fn acquire(
+        &self,
+        device_id: DeviceId,
+        pinned: Option<u8>,
+    ) -> anyhow::Result<(u8, SharedGamepad)> {
+        let slot = self
+            .allocator
+            .lock()
+            .unwrap()
+            .allocate(device_id, pinned)
+            .ok_or_else(|| anyhow::anyhow!("no free gamepad slot for {}", device_id))?;
+
+        let mut drivers = self.drivers.lock().unwrap();
+
+        if let Some(driver) = drivers.get(&slot) {
+            return Ok((slot, driver.clone()));
+        }
+
+        match (self.factory)(slot) {
+            Ok(driver) => {
+                info!("[gamepad_pool]: slot {} created for {}", slot, device_id);
+
+                drivers.insert(slot, driver.clone());
+
+                Ok((slot, driver))
+            }
+            Err(err) => {
+                self.allocator.lock().unwrap().release(&device_id);
+                Err(err)
+            }
+        }
+    }
+
+    pub fn release(&self, device_id: &DeviceId) -> Option<u8> {
+        self.allocator.lock().unwrap().release(device_id)
+    }
+
+    pub fn assignments(&self) -> HashMap<DeviceId, u8> {
+        self.allocator.lock().unwrap().assignments()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_release() {
+        let (first, second) = (DeviceId::new_v4(), DeviceId::new_v4());
+        let mut allocator = SlotAllocator::new(2);
+
+        assert_eq!(allocator.allocate(first, None), Some(1));
+        assert_eq!(allocator.allocate(first, None), Some(1));
+        assert_eq!(allocator.allocate(second, None), Some(2));
+        assert_eq!(allocator.allocate(DeviceId::new_v4(), None), None);
+
+        assert_eq!(allocator.release(&first), Some(1));
+        assert_eq!(allocator.release(&first), None);
+        assert_eq!(allocator.assignments(), HashMap::from([(second, 2)]));
+    }
+
+    #[test]
+    fn test_sticky_slot() {
+        let (first, second) = (DeviceId::new_v4(), DeviceId::new_v4());
+        let mut allocator = SlotAllocator::new(4);
+
+        assert_eq!(allocator.allocate(first, Some(3)), Some(3));
+        assert_eq!(allocator.allocate(second, Some(3)), Some(1));
+
+        allocator.release(&first);
+        allocator.release(&second);
+
+        assert_eq!(allocator.allocate(second, None), Some(1));
+        assert_eq!(allocator.allocate(first, None), Some(3));
+    }
+}
@@ -1,119 +1,147 @@
-use axum::extract::ws::{Message, WebSocket};
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
 
 use jojo_common::driver::gamepad::GamePadAdapter;
-use jojo_common::driver::gamepad::GamepadDriver;
 use jojo_common::driver::keyboard::KeyboardDriver;
 use jojo_common::gamepad::AxisRead;
 use jojo_common::gamepad::HatRead;
 use lazy_static::lazy_static;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
 use std::sync::Mutex;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::db;
+use crate::calibration;
+use crate::command::{CommandReport, CommandSpec, CommandStatus};
+use crate::event::{ControlEvent, DisconnectReason, RoomChange, ServerEvent, SessionInfo};
+use crate::gamepad::SharedGamepad;
+use crate::limit::{Verdict, Violation};
+use crate::ota::Step;
+use crate::protocol::{
+    self, Capability, Encoding, ExtClientMessage, ExtServerMessage, Hello, Inbound, Peer,
+};
+use crate::recorder::{InputRef, RecordedInput, RecordedMessage, Recorder};
+use crate::registry::ButtonId;
+use crate::repeat::{self, Phase, RepeatMode};
+use crate::routing::{self, AxisRoute, PointerAxis, Zone, POINTER_TICK_MILLIS};
+use crate::session::{ActionBatch, Session, GAMEPAD_RETRY};
+use crate::AppState;
 use futures_util::stream::SplitStream;
 use futures_util::{SinkExt, StreamExt};
 use jojo_common::button::ButtonAction;
-use jojo_common::command::{CommandDriver, CustomCommand};
 use jojo_common::device::DeviceId;
 use jojo_common::driver::mouse::MouseDriver;
 use jojo_common::keyboard::KeyboardButton;
-use jojo_common::message::{ClientMessage, ServerMessage};
-use jojo_common::room::RoomEvent;
+use jojo_common::message::ClientMessage;
 use log::*;
-const TIMEOUT_MILLIS: u64 = 10_000;
-const PING_MILLIS: u64 = 5_000;
+
+// Time given to a close frame before the socket is dropped
+const CLOSE_MILLIS: u64 = 1_000;
+// Button action batches waiting behind a slow command before new ones are dropped
+const ACTIONS_CAPACITY: usize = 32;
+// Throttled frames waiting for the bucket before new ones are dropped
+const DEFERRED_CAPACITY: usize = 64;
 
 lazy_static! {
     // TODO: think about replace this with an Arc and passing drivers as a tuple down the functions. Or with OnceCell
     // TODO: review a bug involving USB and vjoy, for some reason while connecting and disconnecting the esp the server loose ownership of the vjoy device and cannot upload anymore
     static ref MOUSE_DRIVER_STACK: Mutex<MouseDriver> = Mutex::new(MouseDriver::default());
     static ref KEYBOARD_DRIVER_STACK: Mutex<KeyboardDriver> = Mutex::new(KeyboardDriver::default());
-    static ref GAMEPAD_DRIVER_STACK: Mutex<GamepadDriver> = Mutex::new(GamepadDriver::default());
 }
 
 pub async fn socket_handler(
     ws: WebSocket,
     device_id: DeviceId,
-    devices: db::Devices,
-    server_to_tauri_tx: tokio::sync::mpsc::Sender<RoomEvent>,
-    mut tauri_to_client_rx: tokio::sync::broadcast::Receiver<ServerMessage>,
+    remote_addr: Option<SocketAddr>,
+    state: AppState,
 ) {
+    // Chosen with the subprotocol at the upgrade, a hello has to keep it
+    let subprotocol = ws
+        .protocol()
+        .and_then(|protocol| protocol.to_str().ok())
+        .and_then(Encoding::from_subprotocols);
     let (mut tx, rx) = ws.split();
+    let info = SessionInfo::new(remote_addr);
 
     // Timeout channel
     let (timeout_tx, mut timeout_rx) = tokio::sync::mpsc::channel::<()>(32);
 
     // Exit socket channel
-    let (exit_tx, mut exit_rx) = tokio::sync::mpsc::channel::<()>(32);
+    let (exit_tx, mut exit_rx) = tokio::sync::mpsc::channel::<DisconnectReason>(32);
     let exit_tx_2 = exit_tx.clone();
     let exit_tx_3 = exit_tx.clone();
+    let exit_tx_4 = exit_tx.clone();
 
     // Ws msg sender channel
     let (ws_sender_tx, mut ws_sender_rx) = tokio::sync::mpsc::channel::<Message>(32);
     let tauri_ws_sender_tx = ws_sender_tx.clone();
 
-    let devices_clone = devices.clone();
-    let tauri_sender_tx_clone = server_to_tauri_tx.clone();
+    let close_tx = ws_sender_tx.clone();
 
-    let read_tauri = tokio::spawn(async move {
-        while let Ok(msg) = tauri_to_client_rx.recv().await {
-            // TODO: need refactor, doing the same thing in all cases
-            match msg {
-                ServerMessage::UpdateDevice(msg_device_id, button_actions)
-                    if msg_device_id == device_id =>
-                {
-                    let message =
-                        bincode::serialize(&ServerMessage::UpdateDevice(device_id, button_actions))
-                            .expect("[read_tauri]: cannot serialize");
-
-                    tauri_ws_sender_tx
-                        .send(Message::Binary(message))
-                        .await
-                        .expect("[read_tauri]: cannot send message");
-                }
-                ServerMessage::RestartDevice(msg_device_id) if msg_device_id == device_id => {
-                    let message = bincode::serialize(&ServerMessage::RestartDevice(device_id))
-                        .expect("[read_tauri]: cannot serialize");
+    let state_clone = state.clone();
+    let (timeout_millis, ping_millis) = (state.config.timeout_millis, state.config.ping_millis);
 
-                    tauri_ws_sender_tx
-                        .send(Message::Binary(message))
-                        .await
-                        .expect("[read_tauri]: cannot send message");
-                }
-                ServerMessage::ClearCredentials(msg_device_id) if msg_device_id == device_id => {
-                    let message = bincode::serialize(&ServerMessage::ClearCredentials(device_id))
-                        .expect("[read_tauri]: cannot serialize");
+    let mut session = Session::new(device_id).with_info(info);
+    session.actions_tx = Some(actions_worker(device_id, state.clone()));
+    session.subprotocol = subprotocol;
+    session.peer.send_replace(Peer {
+        encoding: subprotocol.unwrap_or_default(),
+        ..Peer::default()
+    });
+    let peer_rx = session.peer.subscribe();
+    let (generation, mut device_rx) = state.dispatcher.register(device_id, peer_rx.clone());
+    let connected_at = session.connected_at;
 
-                    tauri_ws_sender_tx
-                        .send(Message::Binary(message))
-                        .await
-                        .expect("[read_tauri]: cannot send message");
-                }
-                _ => info!("[read_tauri]: msg not for us"),
+    let read_tauri = tokio::spawn(async move {
+        while let Some(message) = device_rx.recv().await {
+            let peer = peer_rx.borrow().clone();
+
+            if !peer.accepts(&message) {
+                warn!(
+                    "[read_tauri]: {:?} dropped, protocol version {} of the client doesn't support it",
+                    message, peer.protocol_version
+                );
+                continue;
             }
+
+            let bytes = message
+                .encode_as(peer.encoding)
+                .expect("[read_tauri]: cannot serialize");
+            let message = match peer.encoding.is_text() {
+                true => Message::Text(String::from_utf8(bytes).expect("[read_tauri]: not utf8")),
+                false => Message::Binary(bytes),
+            };
+
+            tauri_ws_sender_tx
+                .send(message)
+                .await
+                .expect("[read_tauri]: cannot send message");
         }
+
+        // The dispatcher only drops the sender when a new session of the device registers
+        exit_tx_4
+            .send(DisconnectReason::Replaced)
+            .await
+            .unwrap_or_else(|_| info!("[read_tauri]: exit_tx send error"));
     });
 
-    // TODO: find a way to propagate errors
-    let read_socket = tokio::spawn(async move {
-        ws_message_handler(
-            rx,
-            timeout_tx,
-            exit_tx_2,
-            devices_clone,
-            tauri_sender_tx_clone,
-        )
-        .await
+    // Stopped between two messages, the session comes back to release what the device left pressed
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+    let mut read_socket = tokio::spawn(async move {
+        ws_message_handler(rx, session, stop_rx, timeout_tx, exit_tx_2, state_clone).await
     });
 
-    let msg_sender = tokio::spawn(async move {
+    let mut msg_sender = tokio::spawn(async move {
         while let Some(msg) = ws_sender_rx.recv().await {
+            let is_close = matches!(msg, Message::Close(_));
+
             match tx.send(msg).await {
+                // Nothing can be sent after a close frame
+                Ok(_) if is_close => break,
                 Ok(_) => {}
                 Err(err) => {
                     error!("[ws]: cannot send msg, err: {}", err);
                     exit_tx_3
-                        .send(())
+                        .send(DisconnectReason::SendError)
                         .await
                         .unwrap_or_else(|_| info!("[timeout_task]: exit_tx send error"));
                     break;
@@ -125,7 +153,7 @@ pub async fn socket_handler(
     // TODO: rewrite this timeout_task, it make me sick
     let timeout_task = tokio::spawn(async move {
         loop {
-            if tokio::time::timeout(Duration::from_millis(TIMEOUT_MILLIS), async {
+            if tokio::time::timeout(Duration::from_millis(timeout_millis), async {
                 if timeout_rx.recv().await.is_some() {}
             })
             .await
@@ -134,52 +162,198 @@ pub async fn socket_handler(
                 break;
             }
         }
-        info!("[ws]: closing connection due to {TIMEOUT_MILLIS}ms timeout");
+        info!("[ws]: closing connection due to {timeout_millis}ms timeout");
         exit_tx
-            .send(())
+            .send(DisconnectReason::Timeout)
             .await
             .unwrap_or_else(|_| info!("[timeout_task]: exit_tx send error"));
     });
 
     let ping_sender = tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_millis(PING_MILLIS));
+        let mut interval = tokio::time::interval(Duration::from_millis(ping_millis));
         loop {
             interval.tick().await;
             ws_sender_tx
-                .send(Message::Ping(vec![]))
+                .send(Message::Ping(ping_payload(connected_at)))
                 .await
                 .unwrap_or_else(|_| info!("[ping_sender]: ws_sender_tx send error"));
         }
     });
 
-    exit_rx
-        .recv()
-        .await
-        .unwrap_or_else(|| info!("[timeout_task]: recv send error"));
+    let reason = exit_rx.recv().await.unwrap_or_else(|| {
+        info!("[timeout_task]: recv send error");
+        DisconnectReason::Closed
+    });
 
-    info!("[ws]: closing thread");
+    info!("[ws]: closing thread, reason: {:?}", reason);
 
     read_tauri.abort();
-    read_socket.abort();
     ping_sender.abort();
     timeout_task.abort();
+
+    stop_tx.send(()).ok();
+
+    let session =
+        match tokio::time::timeout(Duration::from_millis(CLOSE_MILLIS), &mut read_socket).await {
+            Ok(Ok(session)) => Some(session),
+            _ => {
+                warn!(
+                    "[ws]: {} read task didn't stop, its keys stay pressed",
+                    device_id
+                );
+                read_socket.abort();
+                None
+            }
+        };
+
+    if let Some(frame) = close_frame(&reason) {
+        close_tx
+            .send(Message::Close(Some(frame)))
+            .await
+            .unwrap_or_else(|_| info!("[ws]: close_tx send error"));
+
+        // Give the close frame a moment to reach the client
+        tokio::time::timeout(Duration::from_millis(CLOSE_MILLIS), &mut msg_sender)
+            .await
+            .ok();
+    }
+
     msg_sender.abort();
 
-    devices
+    if let Some(mut session) = session {
+        release_pressed(&mut session, &state).await;
+    }
+
+    state.dispatcher.unregister(&device_id, generation);
+    state.limiters.prune(Instant::now());
+    let connected = state.dispatcher.connected();
+    state.metrics.prune(&connected, Instant::now());
+    state.telemetry.prune(&connected, Instant::now());
+
+    state
+        .server_event_tx
+        .send(ServerEvent::Room(
+            device_id,
+            info,
+            RoomChange::Left(reason.clone()),
+        ))
+        .await
+        .unwrap_or_else(|_| info!("[ws]: server_event_tx send error"));
+
+    // The device is still in the room with its new session, which keeps the gamepad slot
+    if reason == DisconnectReason::Replaced {
+        return;
+    }
+
+    release_gamepad(device_id, &state).await;
+
+    state
+        .devices
         .write()
         .await
-        .remove(&device_id, server_to_tauri_tx)
+        .remove(&device_id, state.server_tauri_tx.clone())
         .await;
+
+    state.server_event_tx.publish(ControlEvent::Left(device_id));
+}
+
+// Releases what a device still holds through its buttons and routes, it can't send the release anymore
+async fn release_pressed(session: &mut Session, state: &AppState) {
+    let button_actions = match state.registry.read().await.get(&session.device_id) {
+        Some(config) => {
+            let mut button_actions = session.mapping.release_all(config);
+            button_actions.extend(session.routes.release_zones(&config.routes));
+            button_actions.extend(session.routes.release_directions(&config.hats));
+            button_actions
+        }
+        None => return,
+    };
+
+    if !button_actions.is_empty() {
+        info!(
+            "[release_pressed]: releasing {} actions of {}",
+            button_actions.len(),
+            session.device_id
+        );
+        button_actions_handler(button_actions, session, state).await;
+    }
+}
+
+// Gives the slot back to the pool unless a newer session of the device already took it over
+async fn release_gamepad(device_id: DeviceId, state: &AppState) {
+    if state.dispatcher.connected().contains(&device_id) {
+        return;
+    }
+
+    if let Some(slot) = state.gamepads.release(&device_id) {
+        info!("[ws]: gamepad slot {} of {} released", slot, device_id);
+
+        state
+            .server_event_tx
+            .send(ServerEvent::GamepadSlot(device_id, None))
+            .await
+            .unwrap_or_else(|_| info!("[ws]: server_event_tx send error"));
+    }
+}
+
+// Reasons the client is told about before the socket closes
+fn close_frame(reason: &DisconnectReason) -> Option<CloseFrame<'static>> {
+    let (code, reason) = match reason {
+        DisconnectReason::Incompatible(reason) => (close_code::PROTOCOL, reason.clone()),
+        DisconnectReason::Violation(Violation::FrameTooLarge(bytes)) => (
+            close_code::SIZE,
+            format!("frame of {} bytes is too large", bytes),
+        ),
+        DisconnectReason::Violation(Violation::RateExceeded) => {
+            (close_code::POLICY, "too many messages".to_string())
+        }
+        _ => return None,
+    };
+
+    Some(CloseFrame {
+        code,
+        reason: reason.into(),
+    })
+}
+
+// Clients echo the ping payload in the pong, so it carries the micros since the session started
+fn ping_payload(connected_at: Instant) -> Vec<u8> {
+    (connected_at.elapsed().as_micros() as u64)
+        .to_be_bytes()
+        .to_vec()
+}
+
+// None for pongs that aren't an echo of our pings
+fn round_trip(connected_at: Instant, payload: &[u8]) -> Option<Duration> {
+    let sent = Duration::from_micros(u64::from_be_bytes(payload.try_into().ok()?));
+
+    connected_at.elapsed().checked_sub(sent)
 }
 
 async fn ws_message_handler(
     mut rx: SplitStream<WebSocket>,
+    mut session: Session,
+    mut stop_rx: tokio::sync::oneshot::Receiver<()>,
     timeout_tx: tokio::sync::mpsc::Sender<()>,
-    exit_tx_2: tokio::sync::mpsc::Sender<()>,
-    devices: db::Devices,
-    tauri_sender_tx: tokio::sync::mpsc::Sender<RoomEvent>,
-) -> Result<(), anyhow::Error> {
-    while let Some(result) = rx.next().await {
+    exit_tx_2: tokio::sync::mpsc::Sender<DisconnectReason>,
+    state: AppState,
+) -> Session {
+    // Throttled data frames wait here in order, pongs keep being read in the meantime
+    let mut deferred: VecDeque<Message> = VecDeque::new();
+    let mut retry_at = tokio::time::Instant::now();
+
+    loop {
+        let (result, retried) = tokio::select! {
+            result = rx.next() => match result {
+                Some(result) => (result, false),
+                None => break,
+            },
+            _ = tokio::time::sleep_until(retry_at), if !deferred.is_empty() => {
+                (Ok(deferred.pop_front().expect("[ws]: deferred is empty")), true)
+            }
+            _ = &mut stop_rx => break,
+        };
+
         let msg = match result {
             Ok(msg) => msg,
             Err(err) => {
@@ -190,9 +364,70 @@ async fn ws_message_handler(
             }
         };
 
+        let frame_bytes = match &msg {
+            Message::Text(text) => Some(text.len()),
+            Message::Binary(bytes) => Some(bytes.len()),
+            _ => None,
+        };
+
+        if let Some(frame_bytes) = frame_bytes {
+            // A new frame can't overtake the ones already waiting, an oversized one is still dropped right away
+            let queued = !retried
+                && !deferred.is_empty()
+                && frame_bytes <= state.config.limits.max_frame_bytes;
+            let verdict = match queued {
+                true => Verdict::Throttle(Duration::ZERO),
+                false => state.limiters.check(
+                    session.device_id,
+                    &state.config.limits,
+                    frame_bytes,
+                    Instant::now(),
+                ),
+            };
+            let verdict = match verdict {
+                // Past the capacity the frame is dropped instead of held back
+                Verdict::Throttle(_) if !retried && deferred.len() >= DEFERRED_CAPACITY => {
+                    Verdict::Drop(Violation::RateExceeded)
+                }
+                verdict => verdict,
+            };
+
+            // A retried frame was counted when it was first held back
+            if verdict != Verdict::Accept && !retried {
+                warn!("[limits]: {} {:?}", session.device_id, verdict);
+                state.metrics.record(session.device_id, &verdict);
+            }
+
+            match verdict {
+                Verdict::Accept => {}
+                Verdict::Drop(_) => continue,
+                Verdict::Throttle(wait) => {
+                    if retried || deferred.is_empty() {
+                        retry_at = tokio::time::Instant::now() + wait;
+                    }
+
+                    match retried {
+                        true => deferred.push_front(msg),
+                        false => deferred.push_back(msg),
+                    }
+                    continue;
+                }
+                Verdict::Disconnect(violation) => {
+                    exit_tx_2
+                        .send(DisconnectReason::Violation(violation))
+                        .await
+                        .unwrap_or_else(|_| info!("[limits]: exit_tx_2 send error"));
+                    break;
+                }
+            }
+        }
+
         match msg {
-            Message::Pong(_) => {
-                // info!("[ws]: pong received from");
+            Message::Pong(payload) => {
+                if let Some(rtt) = round_trip(session.connected_at, &payload) {
+                    state.metrics.record_rtt(session.device_id, rtt);
+                }
+
                 timeout_tx
                     .send(())
                     .await
@@ -204,15 +439,23 @@ async fn ws_message_handler(
             Message::Close(_) => {
                 info!("[ws]: close message received");
                 exit_tx_2
-                    .send(())
+                    .send(DisconnectReason::Closed)
                     .await
                     .unwrap_or_else(|_| info!("[msg.is_close()]: exit_tx_2 send error"));
                 break;
             }
             Message::Text(message) => {
-                match serde_json::from_str::<ClientMessage>(&message) {
-                    Ok(client_message) => {
-                        client_message_handler(client_message, &devices, &tauri_sender_tx).await
+                let encoding = session.peer.borrow().encoding;
+
+                match protocol::decode_frame(encoding, message.as_bytes(), true) {
+                    Ok(inbound) => {
+                        if let Err(reason) = inbound_handler(inbound, &mut session, &state).await {
+                            exit_tx_2
+                                .send(reason)
+                                .await
+                                .unwrap_or_else(|_| info!("[text]: exit_tx_2 send error"));
+                            break;
+                        }
                     }
                     Err(err) => {
                         // TODO: this error exist when the payload is bad, for now we are ignoring it
@@ -222,9 +465,17 @@ async fn ws_message_handler(
                 }
             }
             Message::Binary(message) => {
-                match bincode::deserialize::<ClientMessage>(&message) {
-                    Ok(client_message) => {
-                        client_message_handler(client_message, &devices, &tauri_sender_tx).await
+                let encoding = session.peer.borrow().encoding;
+
+                match protocol::decode_frame(encoding, &message, false) {
+                    Ok(inbound) => {
+                        if let Err(reason) = inbound_handler(inbound, &mut session, &state).await {
+                            exit_tx_2
+                                .send(reason)
+                                .await
+                                .unwrap_or_else(|_| info!("[binary]: exit_tx_2 send error"));
+                            break;
+                        }
                     }
                     Err(err) => {
                         // TODO: this error exist when the payload is bad, for now we are ignoring it
@@ -235,19 +486,227 @@ async fn ws_message_handler(
             }
         }
     }
+
+    session
+}
+
+// An error closes the socket with its reason
+async fn inbound_handler(
+    inbound: Inbound,
+    session: &mut Session,
+    state: &AppState,
+) -> Result<(), DisconnectReason> {
+    let started = std::mem::replace(&mut session.started, true);
+
+    match inbound {
+        Inbound::Client(client_message) => {
+            client_message_handler(client_message, session, state).await
+        }
+        Inbound::Extension(ExtClientMessage::Hello(hello)) => {
+            hello_handler(&hello, started, session, state)
+                .map_err(DisconnectReason::Incompatible)?
+        }
+        Inbound::Extension(message) if !session.peer.borrow().sends(&message) => {
+            warn!(
+                "[ws]: {:?} dropped, {} didn't negotiate {:?}",
+                message,
+                session.device_id,
+                message.capability()
+            );
+        }
+        Inbound::Extension(message) => extension_message_handler(message, session, state).await,
+    }
+
+    Ok(())
+}
+
+// Clients without a hello keep the default peer, the one of the first protocol version
+fn hello_handler(
+    hello: &Hello,
+    started: bool,
+    session: &Session,
+    state: &AppState,
+) -> Result<(), String> {
+    let peer = match started {
+        true => Err("the hello must be the first message".to_string()),
+        false => Peer::negotiate(hello, session.subprotocol),
+    }
+    .map_err(|reason| {
+        warn!("[hello]: {} refused, {}", session.device_id, reason);
+        reason
+    })?;
+
+    info!("[hello]: {} negotiated {:?}", session.device_id, peer);
+
+    // The welcome already goes out with the negotiated encoding
+    session.peer.send_replace(peer.clone());
+    state
+        .dispatcher
+        .send(session.device_id, ExtServerMessage::Welcome(peer))
+        .ok();
+
     Ok(())
 }
 
+async fn extension_message_handler(
+    message: ExtClientMessage,
+    session: &mut Session,
+    state: &AppState,
+) {
+    match message {
+        ExtClientMessage::ButtonEvent(button_id, pressed) => {
+            record_handler(InputRef::ButtonEvent(button_id, pressed), session, state).await;
+
+            let registry = state.registry.read().await;
+
+            let Some(config) = registry.get(&session.device_id) else {
+                info!(
+                    "[extension_message_handler]: device {} has no mappings",
+                    session.device_id
+                );
+                return;
+            };
+
+            // Repeats stop before the release actions run, so the button doesn't end pressed
+            if !pressed {
+                session.repeats.stop(&button_id);
+            }
+
+            let (button_actions, changed) = match pressed {
+                true => session.mapping.press(config, button_id),
+                false => session.mapping.release(config, button_id),
+            };
+            let active = session.mapping.active(config);
+            let repeat = match pressed {
+                true => session
+                    .mapping
+                    .held(&button_id)
+                    .filter(|mapping| mapping.repeat.repeats())
+                    .map(|mapping| {
+                        (
+                            mapping.repeat,
+                            repeat::button_actions(&mapping.press),
+                            repeat::button_actions(&mapping.release),
+                        )
+                    }),
+                false => None,
+            };
+
+            drop(registry);
+
+            if changed {
+                info!("[extension_message_handler]: active profile {:?}", active);
+
+                state
+                    .server_event_tx
+                    .send(ServerEvent::ProfileChanged(session.device_id, active))
+                    .await
+                    .unwrap_or_else(|_| info!("[button_event]: server_event_tx send error"));
+            }
+
+            if !button_actions.is_empty() {
+                button_actions_handler(button_actions, session, state).await;
+            }
+
+            if let Some((repeat, press, release)) = repeat {
+                repeat_handler(button_id, repeat, press, release, session, state).await;
+            }
+        }
+        ExtClientMessage::FirmwareAck(update_id, offset) => {
+            let step = state.firmware.ack(&session.device_id, update_id, offset);
+
+            firmware_step_handler(step, session, state).await;
+        }
+        ExtClientMessage::FirmwareDigest(update_id, sha256) => {
+            let step = state
+                .firmware
+                .digest(&session.device_id, update_id, &sha256);
+
+            firmware_step_handler(step, session, state).await;
+        }
+        // Answered by inbound_handler
+        ExtClientMessage::Hello(_) => {}
+        ExtClientMessage::Telemetry(telemetry) => {
+            let alerts = state.telemetry.record(session.device_id, telemetry);
+
+            for alert in alerts {
+                warn!("[telemetry]: {} {:?}", session.device_id, alert);
+
+                state
+                    .server_event_tx
+                    .send(ServerEvent::Telemetry(session.device_id, alert))
+                    .await
+                    .unwrap_or_else(|_| info!("[telemetry]: server_event_tx send error"));
+            }
+        }
+    }
+}
+
+async fn firmware_step_handler(step: Step, session: &Session, state: &AppState) {
+    if let Some(reply) = step.reply {
+        state.dispatcher.send(session.device_id, reply).ok();
+    }
+
+    if let Some(progress) = step.progress {
+        state
+            .server_event_tx
+            .send(ServerEvent::Firmware(session.device_id, progress))
+            .await
+            .unwrap_or_else(|_| info!("[firmware]: server_event_tx send error"));
+    }
+}
+
+// Runs the actions of a held button again until the release arrives
+async fn repeat_handler(
+    button_id: ButtonId,
+    repeat: RepeatMode,
+    press: Vec<ButtonAction>,
+    release: Vec<ButtonAction>,
+    session: &mut Session,
+    state: &AppState,
+) {
+    let device_id = session.device_id;
+    let gamepad = actions_gamepad(&press, session, state).await;
+    let state = state.clone();
+
+    let task = tokio::spawn(async move {
+        let mut index = 0;
+
+        while let Some((wait, phase)) = repeat.step(index) {
+            tokio::time::sleep(wait).await;
+
+            let button_actions = match phase {
+                Phase::Release => release.clone(),
+                Phase::Press => press.clone(),
+            };
+
+            if !button_actions.is_empty() {
+                run_button_actions(device_id, gamepad.clone(), button_actions, &state).await;
+            }
+
+            index += 1;
+        }
+    });
+
+    session.repeats.start(button_id, task);
+}
+
 async fn client_message_handler(
     client_message: ClientMessage,
-    devices: &db::Devices,
-    sender: &tokio::sync::mpsc::Sender<RoomEvent>,
+    session: &mut Session,
+    state: &AppState,
 ) {
     // TODO: use references for drivers, drivers are mutable, so we need a lock or channels to handle multi tasks
     // TODO: Device is an async task, but the rest of the types are sync threads, find a way to re write this
 
+    record_handler(InputRef::Client(&client_message), session, state).await;
+
     match client_message {
         ClientMessage::MouseRead(mouse_read) => {
+            if dry_run(state, &session.device_id, &mouse_read) {
+                return;
+            }
+
             tokio::task::spawn_blocking(move || {
                 let (x_read, y_read) = (mouse_read.x_read(), mouse_read.y_read());
 
@@ -282,88 +741,534 @@ async fn client_message_handler(
             .expect("[mouse_read]: fail case");
         }
         ClientMessage::ButtonActions(button_actions) => {
-            tokio::task::spawn_blocking(move || {
-                for button_action in button_actions {
-                    info!("[client_message_handler]: {:?}", button_action);
-                    match button_action {
-                        ButtonAction::MouseButton(mouse_button, state) => {
-                            MOUSE_DRIVER_STACK
-                                .lock()
-                                .unwrap()
-                                .mouse_button_to_state(mouse_button.to_owned(), state.to_owned());
-                        }
-                        ButtonAction::KeyboardButton(keyboard_button) => match keyboard_button {
-                            KeyboardButton::Sequence(sequence) => KEYBOARD_DRIVER_STACK
-                                .lock()
-                                .unwrap()
-                                .key_sequence(&sequence),
-                            KeyboardButton::SequenceDsl(sequence) => KEYBOARD_DRIVER_STACK
-                                .lock()
-                                .unwrap()
-                                .key_sequence_parse(&sequence),
-                            KeyboardButton::Key(key) => KEYBOARD_DRIVER_STACK
-                                .lock()
-                                .unwrap()
-                                .key_click(key.to_owned()),
-                        },
-                        ButtonAction::GamepadButton(gamepad_button, state) => GAMEPAD_DRIVER_STACK
-                            .lock()
-                            .unwrap()
-                            .gamepad_button_to_state(gamepad_button, state),
-                        ButtonAction::CustomButton(command) => match command {
-                            // TODO: run_binary does his job, but if we want to concatenate a Keyboard command with this
-                            // a "focus" issue appears. Windows focus a program when is started from the cmd. But the timing if the program already exist and
-                            // the program never opened is different, so if we need to send a Key to this program we need to find a way to ensure that the focus
-                            // is en that program
-                            CustomCommand::Binary(path) => {
-                                CommandDriver::run_binary(path)
-                                    .expect("[command_driver]: failed to run binary");
-                                std::thread::sleep(Duration::from_millis(1500));
-                            }
-                        },
-                    }
-                }
-            })
-            .await
-            .expect("[button_actions]: fail case");
+            button_actions_handler(button_actions, session, state).await;
         }
         ClientMessage::AxisRead(axis_read) => {
-            tokio::task::spawn_blocking(move || {
-                info!("[client_message_handler]: {:?}", axis_read);
+            info!("[client_message_handler]: {:?}", axis_read);
 
-                let AxisRead(axis, value) = axis_read;
+            let AxisRead(axis, value) = axis_read;
+            let key = calibration::axis_key(&axis);
 
-                GAMEPAD_DRIVER_STACK
-                    .lock()
-                    .unwrap()
-                    .set_axis(axis, value)
-                    .unwrap();
-            })
-            .await
-            .expect("[axis_read]: fail case");
+            let (axis_route, axis_filter, axis_calibration, capturing) = {
+                let registry = state.registry.read().await;
+
+                (
+                    registry.axis_route(&session.device_id, &key),
+                    registry.axis_filter(&session.device_id, &key),
+                    registry.axis_calibration(&session.device_id, &key),
+                    registry.is_capturing(&session.device_id),
+                )
+            };
+
+            if capturing {
+                state
+                    .registry
+                    .write()
+                    .await
+                    .record_capture(&session.device_id, &key, value as f64);
+            }
+
+            // Filters work on raw values, the calibration maps the result to the gamepad range
+            let filtered = match axis_filter {
+                Some(axis_filter) => match session.filters.entry(key.clone()).or_default().filter(
+                    &axis_filter,
+                    value as f64,
+                    Instant::now(),
+                ) {
+                    Some(filtered) => Some(filtered),
+                    None => return,
+                },
+                None => None,
+            };
+
+            let value = match (filtered, axis_calibration) {
+                (None, None) => value,
+                (filtered, axis_calibration) => {
+                    let filtered = filtered.unwrap_or(value as f64);
+
+                    axis_calibration
+                        .map(|axis_calibration| axis_calibration.apply(filtered))
+                        .unwrap_or(filtered)
+                        .round() as _
+                }
+            };
+
+            match axis_route {
+                AxisRoute::Gamepad => {
+                    if dry_run(state, &session.device_id, (&key, value)) {
+                        return;
+                    }
+
+                    let Some(gamepad) = session_gamepad(session, state).await else {
+                        return;
+                    };
+
+                    tokio::task::spawn_blocking(move || {
+                        gamepad.lock().unwrap().set_axis(axis, value).unwrap();
+                    })
+                    .await
+                    .expect("[axis_read]: fail case");
+                }
+                AxisRoute::Pointer { direction, speed } => {
+                    let speed = routing::deflection(value as f64) * speed;
+
+                    if !dry_run(state, &session.device_id, (direction, speed)) {
+                        pointer_route_handler(session, false, direction, speed);
+                    }
+                }
+                AxisRoute::Scroll { direction, speed } => {
+                    let speed = routing::deflection(value as f64) * speed;
+
+                    if !dry_run(state, &session.device_id, (direction, speed)) {
+                        pointer_route_handler(session, true, direction, speed);
+                    }
+                }
+                AxisRoute::Keys {
+                    negative,
+                    positive,
+                    threshold,
+                } => {
+                    let zone = Zone::new(routing::deflection(value as f64), threshold);
+                    let button_actions =
+                        session.routes.enter_zone(&key, zone, &negative, &positive);
+
+                    if !button_actions.is_empty() {
+                        button_actions_handler(button_actions, session, state).await;
+                    }
+                }
+            }
         }
         ClientMessage::HatRead(hat_read) => {
-            tokio::task::spawn_blocking(move || {
-                info!("[client_message_handler]: {:?}", hat_read);
+            info!("[client_message_handler]: {:?}", hat_read);
+
+            let HatRead(hat, value) = hat_read;
+            let key = calibration::axis_key(&hat);
+
+            let hat_route = state
+                .registry
+                .read()
+                .await
+                .hat_route(&session.device_id, &key);
 
-                let HatRead(hat, value) = hat_read;
+            if let Some(hat_route) = hat_route {
+                let direction = calibration::serde_name(&value);
+                let button_actions = session.routes.enter_direction(&key, direction, &hat_route);
 
-                GAMEPAD_DRIVER_STACK
-                    .lock()
-                    .unwrap()
-                    .set_hat(hat, value)
-                    .unwrap();
+                if !button_actions.is_empty() {
+                    button_actions_handler(button_actions, session, state).await;
+                }
+                return;
+            }
+
+            if dry_run(state, &session.device_id, (&key, &value)) {
+                return;
+            }
+
+            let Some(gamepad) = session_gamepad(session, state).await else {
+                return;
+            };
+
+            tokio::task::spawn_blocking(move || {
+                gamepad.lock().unwrap().set_hat(hat, value).unwrap();
             })
             .await
             .expect("[hat_read]: fail case");
         }
         ClientMessage::Device(device) => {
             // info!("[ws]: saving device {}", device.id());
-            devices
+            let device_id = device.id();
+
+            let previous = state
+                .devices
                 .write()
                 .await
-                .insert(device.id(), device, sender.clone())
+                .insert(device_id, device.clone(), state.server_tauri_tx.clone())
                 .await;
+
+            let change = match previous {
+                Some(_) => RoomChange::Updated(device),
+                None => {
+                    state
+                        .server_event_tx
+                        .publish(ControlEvent::Joined(device_id));
+
+                    RoomChange::Joined(device)
+                }
+            };
+
+            state
+                .server_event_tx
+                .send(ServerEvent::Room(device_id, session.info, change))
+                .await
+                .unwrap_or_else(|_| info!("[ws]: server_event_tx send error"));
+
+            // An update interrupted by a disconnect is offered again, the device resumes it. The hello came
+            // first, a client that didn't negotiate firmware never gets the offer
+            let firmware = session
+                .peer
+                .borrow()
+                .capabilities
+                .contains(&Capability::Firmware);
+
+            if let Some(offer) = firmware
+                .then(|| state.firmware.offer(&session.device_id))
+                .flatten()
+            {
+                state.dispatcher.send(session.device_id, offer).ok();
+            }
+        }
+    }
+}
+
+fn pointer_route_handler(session: &mut Session, scroll: bool, direction: PointerAxis, speed: f64) {
+    session
+        .routes
+        .velocity
+        .lock()
+        .unwrap()
+        .set(scroll, direction, speed);
+
+    if session.routes.ticker.is_none() {
+        session.routes.ticker = Some(pointer_ticker(session));
+    }
+}
+
+// Moves the mouse while an axis routed to the pointer or scroll is deflected, it lives as long as the session
+fn pointer_ticker(session: &Session) -> tokio::task::JoinHandle<()> {
+    let velocity = session.routes.velocity.clone();
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(POINTER_TICK_MILLIS));
+        let mut last_tick = Instant::now();
+
+        loop {
+            interval.tick().await;
+
+            let now = Instant::now();
+            let (x, y, scroll_x, scroll_y) = velocity.lock().unwrap().step(now - last_tick);
+            last_tick = now;
+
+            if (x, y, scroll_x, scroll_y) == (0, 0, 0, 0) {
+                continue;
+            }
+
+            tokio::task::spawn_blocking(move || {
+                if (x, y) != (0, 0) {
+                    MOUSE_DRIVER_STACK.lock().unwrap().mouse_move_relative(x, y);
+                }
+
+                if (scroll_x, scroll_y) != (0, 0) {
+                    routing::scroll(scroll_x, scroll_y);
+                }
+            })
+            .await
+            .expect("[pointer_ticker]: fail case");
         }
+    })
+}
+
+// Opens or closes the session recorder following the registry, then records the message
+async fn record_handler(input: InputRef<'_>, session: &mut Session, state: &AppState) {
+    // A replay recorded again would append to the file it's read from
+    if session.replaying {
+        return;
+    }
+
+    let path = state
+        .registry
+        .read()
+        .await
+        .recording(&session.device_id)
+        .map(|path| path.to_path_buf());
+
+    match (path, &session.recorder) {
+        (None, None) => return,
+        (None, Some(recorder)) => {
+            info!("[record_handler]: {:?} closed", recorder.path());
+            session.recorder = None;
+            return;
+        }
+        (Some(path), Some(recorder)) if recorder.path() == path => {}
+        (Some(path), _) => match Recorder::create(session.device_id, &path) {
+            Ok(recorder) => {
+                info!(
+                    "[record_handler]: recording {} to {:?}",
+                    session.device_id, path
+                );
+                session.recorder = Some(recorder);
+            }
+            Err(err) => {
+                error!("[record_handler]: cannot create {:?}: {}", path, err);
+                state
+                    .registry
+                    .write()
+                    .await
+                    .stop_recording(&session.device_id);
+                return;
+            }
+        },
+    }
+
+    if let Some(recorder) = &mut session.recorder {
+        recorder
+            .record(input)
+            .unwrap_or_else(|err| error!("[record_handler]: cannot record: {}", err));
+    }
+}
+
+// Feeds a recording through the same path as a connected device, a speed of 2 plays it twice as fast
+pub async fn replay(recording: Vec<RecordedMessage>, speed: f64, state: &AppState) {
+    let mut sessions: HashMap<DeviceId, Session> = HashMap::new();
+    let started = tokio::time::Instant::now();
+
+    for recorded in recording {
+        let at = Duration::from_micros(recorded.elapsed_micros).div_f64(speed.max(0.01));
+        tokio::time::sleep_until(started + at).await;
+
+        let session = sessions
+            .entry(recorded.device_id)
+            .or_insert_with(|| Session {
+                replaying: true,
+                ..Session::new(recorded.device_id)
+            });
+
+        match recorded.message {
+            RecordedInput::Client(client_message) => {
+                client_message_handler(client_message, session, state).await
+            }
+            RecordedInput::ButtonEvent(button_id, pressed) => {
+                let message = ExtClientMessage::ButtonEvent(button_id, pressed);
+
+                extension_message_handler(message, session, state).await
+            }
+        }
+    }
+
+    // A recorded device can be connected right now, its slot and its place in the room stay with the live session
+    for (device_id, mut session) in sessions {
+        release_pressed(&mut session, state).await;
+
+        if state.dispatcher.connected().contains(&device_id) {
+            continue;
+        }
+
+        if session.gamepad.is_some() {
+            release_gamepad(device_id, state).await;
+        }
+
+        let mut devices = state.devices.write().await;
+
+        if devices.get(&device_id).is_none() {
+            continue;
+        }
+
+        devices
+            .remove(&device_id, state.server_tauri_tx.clone())
+            .await;
+        drop(devices);
+
+        state
+            .server_event_tx
+            .send(ServerEvent::Room(
+                device_id,
+                session.info,
+                RoomChange::Left(DisconnectReason::ReplayEnded),
+            ))
+            .await
+            .unwrap_or_else(|_| info!("[replay]: server_event_tx send error"));
+
+        state.server_event_tx.publish(ControlEvent::Left(device_id));
+    }
+}
+
+// With dry_run the drivers are skipped, the action is only logged
+fn dry_run(state: &AppState, device_id: &DeviceId, action: impl std::fmt::Debug) -> bool {
+    if state.config.dry_run {
+        info!("[dry_run]: {} {:?}", device_id, action);
+    }
+
+    state.config.dry_run
+}
+
+// Gamepad slot of the session, acquired the first time the device needs one
+async fn session_gamepad(session: &mut Session, state: &AppState) -> Option<SharedGamepad> {
+    if let Some((_, gamepad)) = &session.gamepad {
+        return Some(gamepad.clone());
+    }
+
+    // Gamepad buttons of a dry run are only logged, no vjoy device is taken for them
+    if state.config.dry_run {
+        return None;
+    }
+
+    if session
+        .gamepad_failed_at
+        .is_some_and(|failed_at| failed_at.elapsed() < GAMEPAD_RETRY)
+    {
+        return None;
+    }
+
+    let device_id = session.device_id;
+    let pinned = state
+        .registry
+        .read()
+        .await
+        .get(&device_id)
+        .and_then(|config| config.gamepad_slot);
+    let gamepads = state.gamepads.clone();
+
+    match tokio::task::spawn_blocking(move || gamepads.acquire(device_id, pinned))
+        .await
+        .expect("[session_gamepad]: fail case")
+    {
+        Ok((slot, gamepad)) => {
+            info!("[session_gamepad]: {} got gamepad slot {}", device_id, slot);
+
+            state
+                .server_event_tx
+                .send(ServerEvent::GamepadSlot(device_id, Some(slot)))
+                .await
+                .unwrap_or_else(|_| info!("[session_gamepad]: server_event_tx send error"));
+
+            session.gamepad = Some((slot, gamepad.clone()));
+            session.gamepad_failed_at = None;
+            Some(gamepad)
+        }
+        Err(err) => {
+            error!("[session_gamepad]: {}", err);
+
+            session.gamepad_failed_at = Some(Instant::now());
+            None
+        }
+    }
+}
+
+// Gamepad of the session, only acquired when one of the actions needs it
+async fn actions_gamepad(
+    button_actions: &[ButtonAction],
+    session: &mut Session,
+    state: &AppState,
+) -> Option<SharedGamepad> {
+    match button_actions
+        .iter()
+        .any(|button_action| matches!(button_action, ButtonAction::GamepadButton(_, _)))
+    {
+        true => session_gamepad(session, state).await,
+        false => None,
+    }
+}
+
+async fn button_actions_handler(
+    button_actions: Vec<ButtonAction>,
+    session: &mut Session,
+    state: &AppState,
+) {
+    let gamepad = actions_gamepad(&button_actions, session, state).await;
+
+    match &session.actions_tx {
+        Some(actions_tx) => actions_tx
+            .try_send((gamepad, button_actions))
+            .unwrap_or_else(|err| warn!("[button_actions]: actions dropped, {}", err)),
+        None => run_button_actions(session.device_id, gamepad, button_actions, state).await,
+    }
+}
+
+// Runs the batches of a session one after the other, so a command waiting for its confirmation doesn't stop the
+// socket reader and its pongs. It ends once the session is dropped and the queue is empty.
+fn actions_worker(device_id: DeviceId, state: AppState) -> tokio::sync::mpsc::Sender<ActionBatch> {
+    let (actions_tx, mut actions_rx) = tokio::sync::mpsc::channel::<ActionBatch>(ACTIONS_CAPACITY);
+
+    tokio::spawn(async move {
+        while let Some((gamepad, button_actions)) = actions_rx.recv().await {
+            run_button_actions(device_id, gamepad, button_actions, &state).await;
+        }
+    });
+
+    actions_tx
+}
+
+async fn run_button_actions(
+    device_id: DeviceId,
+    gamepad: Option<SharedGamepad>,
+    button_actions: Vec<ButtonAction>,
+    state: &AppState,
+) {
+    let command_runner = state.command_runner.clone();
+    let blocking_event_tx = state.server_event_tx.clone();
+    let dry_run = state.config.dry_run;
+
+    let reports = tokio::task::spawn_blocking(move || {
+        let mut reports: Vec<CommandReport> = Vec::new();
+
+        for button_action in button_actions {
+            info!("[client_message_handler]: {:?}", button_action);
+
+            // Commands still go through the guard, the runner of a dry run has a backend that starts nothing
+            if dry_run && !matches!(button_action, ButtonAction::CustomButton(_)) {
+                continue;
+            }
+
+            match button_action {
+                ButtonAction::MouseButton(mouse_button, state) => {
+                    MOUSE_DRIVER_STACK
+                        .lock()
+                        .unwrap()
+                        .mouse_button_to_state(mouse_button.to_owned(), state.to_owned());
+                }
+                ButtonAction::KeyboardButton(keyboard_button) => match keyboard_button {
+                    KeyboardButton::Sequence(sequence) => KEYBOARD_DRIVER_STACK
+                        .lock()
+                        .unwrap()
+                        .key_sequence(&sequence),
+                    KeyboardButton::SequenceDsl(sequence) => KEYBOARD_DRIVER_STACK
+                        .lock()
+                        .unwrap()
+                        .key_sequence_parse(&sequence),
+                    KeyboardButton::Key(key) => KEYBOARD_DRIVER_STACK
+                        .lock()
+                        .unwrap()
+                        .key_click(key.to_owned()),
+                },
+                ButtonAction::GamepadButton(gamepad_button, state) => match &gamepad {
+                    Some(gamepad) => gamepad
+                        .lock()
+                        .unwrap()
+                        .gamepad_button_to_state(gamepad_button, state),
+                    None => error!("[button_actions]: no gamepad for {:?}", gamepad_button),
+                },
+                ButtonAction::CustomButton(command) => {
+                    let spec = CommandSpec::from(command);
+                    let report = command_runner.run(device_id, &spec, &blocking_event_tx);
+
+                    if !report.is_success() {
+                        error!(
+                            "[command_runner]: {} failed: {:?}",
+                            spec.program, report.status
+                        );
+                    }
+
+                    // TODO: a detached program gets the focus when is started, but the timing if the program already exist and
+                    // the program never opened is different, so if we need to send a Key to this program we need to find a way to ensure that the focus
+                    // is en that program
+                    if report.status == CommandStatus::Spawned {
+                        std::thread::sleep(Duration::from_millis(1500));
+                    }
+
+                    reports.push(report);
+                }
+            }
+        }
+
+        reports
+    })
+    .await
+    .expect("[button_actions]: fail case");
+
+    for report in reports {
+        state
+            .server_event_tx
+            .send(ServerEvent::CommandReport(device_id, report))
+            .await
+            .unwrap_or_else(|_| info!("[button_actions]: server_event_tx send error"));
     }
 }
@@ -1,61 +1,483 @@
+pub mod access;
+pub mod calibration;
+pub mod command;
+pub mod config;
+pub mod control;
 pub mod db;
+pub mod dispatch;
+pub mod event;
+pub mod filter;
+pub mod gamepad;
 pub mod handler;
+pub mod limit;
+pub mod metrics;
+pub mod ota;
+pub mod policy;
+pub mod protocol;
+pub mod recorder;
+pub mod registry;
+pub mod repeat;
+pub mod routing;
+pub mod session;
+pub mod telemetry;
 
+use crate::access::{ConnectionTracker, Rejection};
+use crate::command::CommandRunner;
+use crate::config::ServerConfig;
 use crate::db::Devices;
-use axum::extract::{Path, State};
-use axum::{extract::ws::WebSocketUpgrade, routing::get, Router};
+use crate::dispatch::Dispatcher;
+use crate::event::{EventSender, ServerEvent};
+use crate::gamepad::GamepadPool;
+use crate::limit::Limiters;
+use crate::metrics::{Metrics, Violations};
+use crate::ota::FirmwareUpdates;
+use crate::protocol::Encoding;
+use crate::registry::Registry;
+use crate::telemetry::{TelemetrySample, TelemetryStore};
+use axum::extract::{ConnectInfo, Path, Query, Request, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{Html, IntoResponse, Response};
+use axum::{extract::ws::WebSocketUpgrade, routing::get, Json, Router};
 use jojo_common::device::DeviceId;
-use std::net::Ipv4Addr;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
+// Served at /, it only talks to the apis below
+const DASHBOARD: &str = include_str!("dashboard.html");
+
 #[derive(Clone)]
-struct AppState {
-    devices: Devices,
-    server_tauri_tx: tokio::sync::mpsc::Sender<jojo_common::room::RoomEvent>,
-    tauri_client_tx: tokio::sync::broadcast::Sender<jojo_common::message::ServerMessage>,
+pub struct AppState {
+    pub(crate) devices: Devices,
+    pub(crate) server_tauri_tx: tokio::sync::mpsc::Sender<jojo_common::room::RoomEvent>,
+    pub(crate) tauri_client_tx: tokio::sync::broadcast::Sender<jojo_common::message::ServerMessage>,
+    pub(crate) server_event_tx: EventSender,
+    pub(crate) command_runner: CommandRunner,
+    pub(crate) registry: Registry,
+    pub(crate) gamepads: GamepadPool,
+    pub(crate) config: ServerConfig,
+    pub(crate) dispatcher: Dispatcher,
+    pub(crate) telemetry: TelemetryStore,
+    pub(crate) firmware: FirmwareUpdates,
+    pub(crate) metrics: Metrics,
+    pub(crate) limiters: Limiters,
+    pub(crate) connections: ConnectionTracker,
+}
+
+impl AppState {
+    pub fn new(
+        server_tauri_tx: tokio::sync::mpsc::Sender<jojo_common::room::RoomEvent>,
+        tauri_client_tx: tokio::sync::broadcast::Sender<jojo_common::message::ServerMessage>,
+        server_event_tx: tokio::sync::mpsc::Sender<ServerEvent>,
+        command_runner: CommandRunner,
+        registry: Registry,
+    ) -> Self {
+        AppState {
+            devices: Arc::new(RwLock::new(db::DeviceMap::new())),
+            server_tauri_tx,
+            tauri_client_tx,
+            server_event_tx: EventSender::from(server_event_tx),
+            command_runner,
+            registry,
+            gamepads: GamepadPool::default(),
+            config: ServerConfig::default(),
+            dispatcher: Dispatcher::new(),
+            telemetry: TelemetryStore::default(),
+            firmware: FirmwareUpdates::default(),
+            metrics: Metrics::default(),
+            limiters: Limiters::default(),
+            connections: ConnectionTracker::default(),
+        }
+    }
+
+    pub fn with_config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
 }
 
+// The entry point the app builds against, its signature stays the same as the server grows. Anything past the
+// defaults (command guard, registry, config, a listener for server events) goes through AppState::new and bind
 pub async fn initialize(
     ip_address: Ipv4Addr,
     port: u16,
     server_tauri_tx: tokio::sync::mpsc::Sender<jojo_common::room::RoomEvent>,
     tauri_client_tx: tokio::sync::broadcast::Sender<jojo_common::message::ServerMessage>,
 ) {
-    let devices = Arc::new(RwLock::new(db::DeviceMap::new()));
-    let shared_state = AppState {
-        devices,
+    let (server_event_tx, mut server_event_rx) = tokio::sync::mpsc::channel::<ServerEvent>(32);
+
+    tokio::spawn(async move {
+        while let Some(event) = server_event_rx.recv().await {
+            log::info!("[server_events]: {:?}", event);
+        }
+    });
+
+    // Nobody can answer a confirmation here, commands outside the allowlist are denied
+    let command_runner = CommandRunner::new(
+        Arc::new(command::ProcessBackend::default()),
+        policy::CommandGuard::new(policy::CommandPolicy {
+            unknown: policy::UnknownCommand::Deny,
+            ..Default::default()
+        }),
+    );
+
+    let shared_state = AppState::new(
         server_tauri_tx,
         tauri_client_tx,
-    };
+        server_event_tx,
+        command_runner,
+        Registry::default(),
+    );
 
-    let app =
-        Router::new()
-            .route(
-                "/ws/:id",
-                get(
-                    |Path(id): Path<DeviceId>,
-                     ws: WebSocketUpgrade,
-                     State(state): State<AppState>| async move {
-                        ws.on_upgrade(move |socket| {
-                            handler::socket_handler(
-                                socket,
-                                id,
-                                state.devices.clone(),
-                                state.server_tauri_tx.clone(),
-                                state.tauri_client_tx.subscribe(),
-                            )
-                        })
-                    },
-                ),
-            )
-            .with_state(shared_state);
-
-    let listener = tokio::net::TcpListener::bind((ip_address, port))
+    bind(ip_address, port, shared_state)
+        .await
+        .unwrap()
+        .serve()
         .await
         .unwrap();
+}
+
+// A bound server that isn't accepting connections yet, port 0 picks a free one
+pub struct Server {
+    listener: tokio::net::TcpListener,
+    app: Router,
+}
 
-    axum::serve(listener, app.into_make_service())
+impl Server {
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub async fn serve(self) -> std::io::Result<()> {
+        axum::serve(
+            self.listener,
+            self.app
+                .into_make_service_with_connect_info::<std::net::SocketAddr>(),
+        )
         .await
-        .unwrap();
+    }
+}
+
+pub async fn bind(ip_address: Ipv4Addr, port: u16, state: AppState) -> std::io::Result<Server> {
+    // The only subscriber of the app channel, messages go to the session of their device from here
+    let mut tauri_client_rx = state.tauri_client_tx.subscribe();
+    let state_clone = state.clone();
+    tokio::spawn(async move {
+        loop {
+            match tauri_client_rx.recv().await {
+                Ok(message) => app_message_handler(message, &state_clone).await,
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                    log::warn!("[app_messages]: lagged, {} messages skipped", skipped)
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    let app = router(state, Listener::Network);
+
+    let listener = tokio::net::TcpListener::bind((ip_address, port)).await?;
+
+    Ok(Server { listener, app })
+}
+
+async fn app_message_handler(message: jojo_common::message::ServerMessage, state: &AppState) {
+    // Mappings are kept on the server too, so devices resolving them here see updates even while offline
+    if let jojo_common::message::ServerMessage::UpdateDevice(device_id, buttons) = &message {
+        state
+            .registry
+            .write()
+            .await
+            .set_buttons(*device_id, buttons.clone());
+    }
+
+    let Some(device_id) = dispatch::message_device_id(&message) else {
+        log::warn!("[app_messages]: no device for {:?}", message);
+        return;
+    };
+
+    if let Err(undelivered) = state.dispatcher.send(device_id, message) {
+        state
+            .server_event_tx
+            .send(ServerEvent::Undelivered(device_id, undelivered))
+            .await
+            .unwrap_or_else(|_| log::info!("[app_messages]: server_event_tx send error"));
+    }
+}
+
+// No connect info on the local socket
+async fn ws_upgrade_handler(
+    Path(id): Path<DeviceId>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+) -> Response {
+    let remote_addr = connect_info.map(|ConnectInfo(addr)| addr);
+
+    if let Err(rejection) = check_origin(&headers, &state) {
+        log::warn!(
+            "[ws]: {} from {:?} rejected, {}",
+            id,
+            remote_addr,
+            rejection
+        );
+
+        return (StatusCode::FORBIDDEN, rejection.to_string()).into_response();
+    }
+
+    let guard = match state.connections.admit(
+        remote_addr.map(|addr| addr.ip()),
+        Some(id),
+        &state.config.access,
+    ) {
+        Ok(guard) => guard,
+        Err(rejection) => {
+            log::warn!(
+                "[ws]: {} from {:?} rejected, {}",
+                id,
+                remote_addr,
+                rejection
+            );
+
+            return admit_rejected(rejection);
+        }
+    };
+
+    // Axum picks the first of the server list, the client preference wins here
+    let encoding = headers
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|value| value.to_str().ok())
+        .and_then(Encoding::from_subprotocols);
+
+    let max_bytes = state
+        .config
+        .limits
+        .max_frame_bytes
+        .saturating_mul(limit::FRAME_HEADROOM);
+
+    ws.protocols(encoding.map(|encoding| encoding.subprotocol()))
+        .max_frame_size(max_bytes)
+        .max_message_size(max_bytes)
+        .on_upgrade(move |socket| async move {
+            handler::socket_handler(socket, id, remote_addr, state).await;
+            drop(guard);
+        })
+}
+
+fn admit_rejected(rejection: Rejection) -> Response {
+    let status = match rejection {
+        Rejection::Denied(..) | Rejection::NotAllowed(_) => StatusCode::FORBIDDEN,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    (status, rejection.to_string()).into_response()
+}
+
+fn check_origin(headers: &HeaderMap, state: &AppState) -> Result<(), Rejection> {
+    let header = |name: header::HeaderName| {
+        headers
+            .get(name)
+            .map(|value| value.to_str().unwrap_or_default())
+    };
+
+    state
+        .config
+        .access
+        .check_origin(header(header::ORIGIN), header(header::HOST))
+}
+
+async fn control_upgrade_handler(
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+) -> Response {
+    if let Err(rejection) = check_origin(&headers, &state) {
+        log::warn!("[control]: rejected, {}", rejection);
+
+        return (StatusCode::FORBIDDEN, rejection.to_string()).into_response();
+    }
+
+    // Control sockets aren't devices but still count against the connections of their address
+    let guard = match state.connections.admit(
+        connect_info.map(|ConnectInfo(addr)| addr.ip()),
+        None,
+        &state.config.access,
+    ) {
+        Ok(guard) => guard,
+        Err(rejection) => {
+            log::warn!("[control]: rejected, {}", rejection);
+
+            return admit_rejected(rejection);
+        }
+    };
+
+    ws.on_upgrade(move |socket| async move {
+        control::control_handler(socket, state).await;
+        drop(guard);
+    })
+}
+
+// The allow and deny lists cover every route on the network, not only the device sockets
+async fn check_address(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Err(rejection) = state.config.access.check(addr.ip()) {
+        log::warn!(
+            "[access]: {} from {} rejected, {}",
+            request.uri().path(),
+            addr,
+            rejection
+        );
+
+        return (StatusCode::FORBIDDEN, rejection.to_string()).into_response();
+    }
+
+    next.run(request).await
+}
+
+// Operator routes on the network need the control token, as a bearer header or as a token query parameter since
+// browsers can't set headers on websockets
+async fn authorize(
+    State(state): State<AppState>,
+    Query(query): Query<HashMap<String, String>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or(query.get("token").map(String::as_str));
+
+    if let Err(rejection) = state.config.access.check_token(token) {
+        log::warn!(
+            "[control]: {} rejected, {}",
+            request.uri().path(),
+            rejection
+        );
+
+        let status = match rejection {
+            Rejection::NoToken => StatusCode::FORBIDDEN,
+            _ => StatusCode::UNAUTHORIZED,
+        };
+
+        return (status, rejection.to_string()).into_response();
+    }
+
+    next.run(request).await
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Listener {
+    Network,
+    // Only the user running the server can connect to it
+    Local,
+}
+
+fn router(state: AppState, listener: Listener) -> Router {
+    // Whoever reaches these drives every device and confirms commands
+    let operator = Router::new()
+        .route("/", get(|| async { Html(DASHBOARD) }))
+        .route(
+            "/devices/:id/telemetry",
+            get(
+                |Path(id): Path<DeviceId>, State(state): State<AppState>| async move {
+                    Json::<Vec<TelemetrySample>>(state.telemetry.history(&id))
+                },
+            ),
+        )
+        .route(
+            "/metrics",
+            get(|State(state): State<AppState>| async move {
+                Json::<HashMap<DeviceId, Violations>>(state.metrics.violations())
+            }),
+        )
+        .route("/control", get(control_upgrade_handler));
+
+    let operator = match listener {
+        Listener::Network => {
+            operator.route_layer(middleware::from_fn_with_state(state.clone(), authorize))
+        }
+        Listener::Local => operator,
+    };
+
+    let app = Router::new()
+        .route("/ws/:id", get(ws_upgrade_handler))
+        .merge(operator);
+
+    match listener {
+        Listener::Network => {
+            app.layer(middleware::from_fn_with_state(state.clone(), check_address))
+        }
+        Listener::Local => app,
+    }
+    .with_state(state)
+}
+
+// Same router on a unix socket, so the app can reach a server running as its own process.
+// Bind it with the state given to `bind`, which also keeps the registry in sync.
+#[cfg(unix)]
+pub struct LocalServer {
+    listener: tokio::net::UnixListener,
+    app: Router,
+}
+
+#[cfg(unix)]
+impl LocalServer {
+    pub async fn serve(self) -> std::io::Result<()> {
+        use tower::Service;
+
+        loop {
+            let socket = match self.listener.accept().await {
+                Ok((socket, _)) => hyper_util::rt::TokioIo::new(socket),
+                Err(err) => {
+                    log::error!("[local_server]: accept error: {}", err);
+                    continue;
+                }
+            };
+            let app = self.app.clone();
+
+            tokio::spawn(async move {
+                let service = hyper::service::service_fn(
+                    move |request: hyper::Request<hyper::body::Incoming>| app.clone().call(request),
+                );
+
+                hyper_util::server::conn::auto::Builder::new(hyper_util::rt::TokioExecutor::new())
+                    // upgrades needed for websockets
+                    .serve_connection_with_upgrades(socket, service)
+                    .await
+                    .unwrap_or_else(|err| log::info!("[local_server]: connection error: {}", err));
+            });
+        }
+    }
+}
+
+#[cfg(unix)]
+pub async fn bind_local(path: &std::path::Path, state: AppState) -> std::io::Result<LocalServer> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    // A socket left by a previous run would make the bind fail, anything else at the path is kept
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+
+    let listener = tokio::net::UnixListener::bind(path)?;
+
+    // Whoever can connect drives the keyboard and mouse, keep it to the current user
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+
+    Ok(LocalServer {
+        listener,
+        app: router(state, Listener::Local),
+    })
 }
@@ -0,0 +1,284 @@
+use jojo_common::device::DeviceId;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// The socket itself refuses frames this many times over the limit, below that the policy decides
+pub const FRAME_HEADROOM: usize = 4;
+// Longest a throttled frame waits before the bucket is checked again, slow rates just check more than once
+pub const MAX_THROTTLE: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ViolationPolicy {
+    // The message is ignored
+    #[default]
+    Drop,
+    // Messages wait in order until the bucket has a token again, oversized frames are dropped
+    Throttle,
+    // The socket is closed with a close code
+    Disconnect,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    pub max_frame_bytes: usize,
+    // Sustained rate of data frames, axes stream at about 100 per second
+    #[serde(deserialize_with = "positive_rate")]
+    pub messages_per_second: f64,
+    pub burst: u32,
+    pub policy: ViolationPolicy,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_frame_bytes: 64 * 1024,
+            messages_per_second: 250.0,
+            burst: 500,
+            policy: ViolationPolicy::Drop,
+        }
+    }
+}
+
+// A zero rate would throttle or drop every frame after the burst forever
+fn positive_rate<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    let rate = f64::deserialize(deserializer)?;
+
+    match rate.is_finite() && rate > 0.0 {
+        true => Ok(rate),
+        false => Err(D::Error::custom(format!(
+            "messages_per_second must be a positive number, got {}",
+            rate
+        ))),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Violation {
+    FrameTooLarge(usize),
+    RateExceeded,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    Drop(Violation),
+    // Check again after the wait
+    Throttle(Duration),
+    Disconnect(Violation),
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, capacity: u32, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            capacity: capacity.max(1) as f64,
+            tokens: capacity.max(1) as f64,
+            updated: now,
+        }
+    }
+
+    // Err is the time until the next token, at most MAX_THROTTLE
+    pub fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+
+        // A rate of zero waits forever, a tiny one longer than a Duration holds
+        let wait = Duration::try_from_secs_f64((1.0 - self.tokens) / self.rate)
+            .map_or(MAX_THROTTLE, |wait| wait.min(MAX_THROTTLE));
+
+        Err(wait)
+    }
+
+    pub fn is_full(&self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+
+        self.tokens + elapsed * self.rate >= self.capacity
+    }
+}
+
+// Limits of one device, checked for every data frame
+#[derive(Debug, Clone)]
+pub struct Limiter {
+    limits: Limits,
+    bucket: TokenBucket,
+}
+
+impl Limiter {
+    pub fn new(limits: Limits) -> Self {
+        Limiter {
+            bucket: TokenBucket::new(limits.messages_per_second, limits.burst, Instant::now()),
+            limits,
+        }
+    }
+
+    pub fn check(&mut self, frame_bytes: usize, now: Instant) -> Verdict {
+        let violation = match frame_bytes > self.limits.max_frame_bytes {
+            true => Violation::FrameTooLarge(frame_bytes),
+            false => match self.bucket.take(now) {
+                Ok(()) => return Verdict::Accept,
+                Err(wait) if self.limits.policy == ViolationPolicy::Throttle => {
+                    return Verdict::Throttle(wait)
+                }
+                Err(_) => Violation::RateExceeded,
+            },
+        };
+
+        match self.limits.policy {
+            ViolationPolicy::Disconnect => Verdict::Disconnect(violation),
+            _ => Verdict::Drop(violation),
+        }
+    }
+}
+
+// Buckets are kept per device, a reconnect or a second socket doesn't get a fresh one
+#[derive(Debug, Clone, Default)]
+pub struct Limiters {
+    limiters: Arc<Mutex<HashMap<DeviceId, Limiter>>>,
+}
+
+impl Limiters {
+    pub fn check(
+        &self,
+        device_id: DeviceId,
+        limits: &Limits,
+        frame_bytes: usize,
+        now: Instant,
+    ) -> Verdict {
+        self.limiters
+            .lock()
+            .unwrap()
+            .entry(device_id)
+            .or_insert_with(|| Limiter::new(limits.clone()))
+            .check(frame_bytes, now)
+    }
+
+    // A bucket that filled up again is the same as a new one
+    pub fn prune(&self, now: Instant) {
+        self.limiters
+            .lock()
+            .unwrap()
+            .retain(|_, limiter| !limiter.bucket.is_full(now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 2, now);
+
+        assert!(bucket.take(now).is_ok());
+        assert!(bucket.take(now).is_ok());
+        assert_eq!(bucket.take(now), Err(Duration::from_millis(100)));
+        assert!(bucket.take(now + Duration::from_millis(100)).is_ok());
+        // Idle time doesn't go past the burst
+        assert!(bucket.take(now + Duration::from_secs(10)).is_ok());
+        assert!(bucket.take(now + Duration::from_secs(10)).is_ok());
+        assert!(bucket.take(now + Duration::from_secs(10)).is_err());
+
+        for rate in [0.0, 1e-300] {
+            let mut bucket = TokenBucket::new(rate, 1, now);
+
+            assert!(bucket.take(now).is_ok());
+            assert_eq!(bucket.take(now), Err(MAX_THROTTLE));
+        }
+    }
+
+    #[test]
+    fn test_rate_config() {
+        let limits: Limits = serde_json::from_str(r#"{"messages_per_second": 20.0}"#).unwrap();
+
+        assert_eq!(limits.messages_per_second, 20.0);
+        assert_eq!(limits.burst, Limits::default().burst);
+        assert!(serde_json::from_str::<Limits>(r#"{"messages_per_second": 0}"#).is_err());
+        assert!(serde_json::from_str::<Limits>(r#"{"messages_per_second": -5.0}"#).is_err());
+    }
+
+    #[test]
+    fn test_policies() {
+        let now = Instant::now();
+        let limiter = |policy| {
+            Limiter::new(Limits {
+                max_frame_bytes: 10,
+                messages_per_second: 1.0,
+                burst: 1,
+                policy,
+            })
+        };
+
+        let mut drop = limiter(ViolationPolicy::Drop);
+
+        assert_eq!(
+            drop.check(11, now),
+            Verdict::Drop(Violation::FrameTooLarge(11))
+        );
+        assert_eq!(drop.check(10, now), Verdict::Accept);
+        assert_eq!(drop.check(10, now), Verdict::Drop(Violation::RateExceeded));
+
+        let mut throttle = limiter(ViolationPolicy::Throttle);
+
+        assert_eq!(throttle.check(10, now), Verdict::Accept);
+        assert!(matches!(throttle.check(10, now), Verdict::Throttle(_)));
+        assert_eq!(
+            throttle.check(11, now),
+            Verdict::Drop(Violation::FrameTooLarge(11))
+        );
+
+        let mut disconnect = limiter(ViolationPolicy::Disconnect);
+
+        assert_eq!(disconnect.check(10, now), Verdict::Accept);
+        assert_eq!(
+            disconnect.check(10, now),
+            Verdict::Disconnect(Violation::RateExceeded)
+        );
+    }
+
+    #[test]
+    fn test_limiters() {
+        let now = Instant::now();
+        let (device_id, other_id) = (DeviceId::new_v4(), DeviceId::new_v4());
+        let limits = Limits {
+            messages_per_second: 1.0,
+            burst: 1,
+            ..Default::default()
+        };
+        let limiters = Limiters::default();
+
+        assert_eq!(limiters.check(device_id, &limits, 1, now), Verdict::Accept);
+        assert_eq!(limiters.check(other_id, &limits, 1, now), Verdict::Accept);
+
+        // Pruning keeps the empty bucket, the next socket of the device can't start over
+        limiters.prune(now);
+
+        assert_eq!(
+            limiters.check(device_id, &limits, 1, now),
+            Verdict::Drop(Violation::RateExceeded)
+        );
+
+        limiters.prune(now + Duration::from_secs(2));
+
+        assert!(limiters.limiters.lock().unwrap().is_empty());
+    }
+}
@@ -1,13 +1,7 @@
 use futures_util::future::join;
-use jojo_common::button::ButtonAction;
-use jojo_common::command::CustomCommand;
-use jojo_common::keyboard::{Key, KeyboardButton};
-use jojo_server::initialize;
 use log::*;
-use std::collections::HashMap;
 use std::net::Ipv4Addr;
-use std::time::Duration;
-use uuid::uuid;
+use std::path::Path;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -17,58 +11,115 @@ async fn main() -> anyhow::Result<()> {
     let (server_to_tauri_tx, mut server_to_tauri_rx) =
         tokio::sync::mpsc::channel::<jojo_common::room::RoomEvent>(32);
 
+    let (server_event_tx, mut server_event_rx) =
+        tokio::sync::mpsc::channel::<jojo_server::event::ServerEvent>(32);
+
     let (tauri_to_client_tx, _) = tokio::sync::broadcast::channel(16);
-    let tauri_to_client_tx_clone = tauri_to_client_tx.clone();
 
     let ip_local = Ipv4Addr::new(192, 168, 0, 163);
     let port = 3000;
 
-    let task = initialize(ip_local, port, server_to_tauri_tx, tauri_to_client_tx);
-
-    let server = tokio::spawn(task);
-
-    // let tauri_to_client_listener = tokio::spawn(async move {
-    //     info!("[tauri_to_client_listener]: waiting 5s to send event");
-
-    //     tokio::time::sleep(Duration::from_secs(10)).await;
-
-    //     let button_actions: Vec<jojo_common::button::ButtonAction> = vec![
-    //         ButtonAction::CustomButton(CustomCommand::Binary(
-    //             "C:\\Users\\gggiu\\AppData\\Roaming\\Spotify\\Spotify.exe".to_string(),
-    //         )),
-    //         ButtonAction::KeyboardButton(KeyboardButton::Key(Key::Space)),
-    //     ];
-
-    //     // tauri_to_client_tx_clone
-    //     //     .send(jojo_common::message::ServerMessage::RestartDevice(uuid!(
-    //     //         "58c79037-d101-476d-bcbe-1503e9011261"
-    //     //     )))
-    //     //     .unwrap();
-
-    //     let message = jojo_common::message::ServerMessage::UpdateDevice(
-    //         uuid!("58c79037-d101-476d-bcbe-1503e9011261"),
-    //         HashMap::from([(
-    //             uuid!("0ce7ecdb-4dcc-46f5-804c-65a39d2277a0"),
-    //             button_actions,
-    //         )]),
-    //     );
-
-    //     tauri_to_client_tx_clone.send(message).unwrap();
-
-    //     info!("[tauri_to_client_listener]: event send");
-    // });
+    // jojo-server replay <recording> [speed] [--dry-run]
+    let mut args: Vec<String> = std::env::args().collect();
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    args.retain(|arg| arg != "--dry-run");
+
+    let backend: std::sync::Arc<dyn jojo_server::command::CommandBackend> = match dry_run {
+        true => std::sync::Arc::new(jojo_server::command::DryRunBackend),
+        false => std::sync::Arc::new(jojo_server::command::ProcessBackend::default()),
+    };
+    // The listener below only logs, confirmations are answered by a /control client or denied
+    let command_runner = jojo_server::command::CommandRunner::new(
+        backend,
+        jojo_server::policy::CommandGuard::default().with_app_confirmations(false),
+    )
+    .with_cleanup_on_shutdown(true);
 
     let server_to_tauri_listener = tokio::spawn(async move {
         info!("LISTENING");
 
-        while let Some(event) = server_to_tauri_rx.recv().await {
-            info!("EVENT EMITTED: {:?}", event)
+        loop {
+            tokio::select! {
+                Some(event) = server_to_tauri_rx.recv() => info!("EVENT EMITTED: {:?}", event),
+                Some(event) = server_event_rx.recv() => info!("SERVER EVENT EMITTED: {:?}", event),
+                else => break,
+            }
         }
     });
 
-    // tauri_to_client_listener.await.unwrap();
-
-    let (_, _) = join(server, server_to_tauri_listener).await;
+    if args.get(1).map(String::as_str) == Some("replay") {
+        let path = args.get(2).ok_or_else(|| {
+            anyhow::anyhow!("usage: jojo-server replay <recording> [speed] [--dry-run]")
+        })?;
+        let speed = args
+            .get(3)
+            .map(|speed| speed.parse::<f64>())
+            .transpose()?
+            .unwrap_or(1.0);
+
+        let recording = jojo_server::recorder::read_recording(Path::new(path))?;
+        info!(
+            "[main]: replaying {} messages from {}",
+            recording.len(),
+            path
+        );
+
+        let state = jojo_server::AppState::new(
+            server_to_tauri_tx,
+            tauri_to_client_tx,
+            server_event_tx,
+            command_runner.clone(),
+            jojo_server::registry::Registry::default(),
+        )
+        .with_config(jojo_server::config::ServerConfig {
+            dry_run,
+            ..Default::default()
+        });
+
+        jojo_server::handler::replay(recording, speed, &state).await;
+
+        // Closing the channels ends the listener once it logged every event
+        drop(state);
+        server_to_tauri_listener.await?;
+        command_runner.shutdown();
+
+        return Ok(());
+    }
+
+    let mut config = jojo_server::config::ServerConfig::default();
+    // Without it the dashboard and /control are only served on JOJO_LOCAL_SOCKET
+    config.access.control_token = std::env::var("JOJO_CONTROL_TOKEN").ok();
+    config.dry_run = dry_run;
+
+    let state = jojo_server::AppState::new(
+        server_to_tauri_tx,
+        tauri_to_client_tx,
+        server_event_tx,
+        command_runner.clone(),
+        jojo_server::registry::Registry::default(),
+    )
+    .with_config(config);
+
+    let server = jojo_server::bind(ip_local, port, state.clone()).await?;
+    info!("[main]: listening on {}", server.local_addr()?);
+
+    // The app can run the server as its own process and connect through this socket
+    #[cfg(unix)]
+    if let Ok(path) = std::env::var("JOJO_LOCAL_SOCKET") {
+        let local_server = jojo_server::bind_local(Path::new(&path), state.clone()).await?;
+        info!("[main]: listening on {}", path);
+
+        tokio::spawn(local_server.serve());
+    }
+
+    let server = tokio::spawn(server.serve());
+
+    tokio::select! {
+        _ = join(server, server_to_tauri_listener) => {}
+        _ = tokio::signal::ctrl_c() => info!("[main]: ctrl-c received, shutting down"),
+    }
+
+    command_runner.shutdown();
 
     Ok(())
 }
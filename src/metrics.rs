@@ -0,0 +1,127 @@
+use crate::limit::{Verdict, Violation};
+use jojo_common::device::DeviceId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Violations of a disconnected device are kept this long after the last one, so the reason it was cut off
+// can still be looked up
+pub const VIOLATIONS_TTL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Violations {
+    pub oversized_frames: u64,
+    pub rate_exceeded: u64,
+    pub dropped: u64,
+    pub throttled: u64,
+    pub disconnected: u64,
+}
+
+// Counters since the device connected, kept for a while after it disconnects
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    // With the time of the last violation
+    violations: Arc<Mutex<HashMap<DeviceId, (Violations, Instant)>>>,
+    // Last ping round trip of each device
+    rtts: Arc<Mutex<HashMap<DeviceId, Duration>>>,
+}
+
+impl Metrics {
+    pub fn record(&self, device_id: DeviceId, verdict: &Verdict) {
+        if *verdict == Verdict::Accept {
+            return;
+        }
+
+        let mut violations = self.violations.lock().unwrap();
+        let (counts, violated_at) = violations
+            .entry(device_id)
+            .or_insert_with(|| (Violations::default(), Instant::now()));
+
+        *violated_at = Instant::now();
+
+        let violation = match verdict {
+            Verdict::Accept => return,
+            Verdict::Drop(violation) => {
+                counts.dropped += 1;
+                *violation
+            }
+            // Throttling only happens to frames within the size limit
+            Verdict::Throttle(_) => {
+                counts.throttled += 1;
+                Violation::RateExceeded
+            }
+            Verdict::Disconnect(violation) => {
+                counts.disconnected += 1;
+                *violation
+            }
+        };
+
+        match violation {
+            Violation::FrameTooLarge(_) => counts.oversized_frames += 1,
+            Violation::RateExceeded => counts.rate_exceeded += 1,
+        }
+    }
+
+    pub fn violations(&self) -> HashMap<DeviceId, Violations> {
+        self.violations
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(device_id, (counts, _))| (*device_id, counts.clone()))
+            .collect()
+    }
+
+    pub fn record_rtt(&self, device_id: DeviceId, rtt: Duration) {
+        self.rtts.lock().unwrap().insert(device_id, rtt);
+    }
+
+    pub fn rtt(&self, device_id: &DeviceId) -> Option<Duration> {
+        self.rtts.lock().unwrap().get(device_id).copied()
+    }
+
+    // Round trips only mean something while connected, violations go once they are old enough
+    pub fn prune(&self, connected: &[DeviceId], now: Instant) {
+        self.rtts
+            .lock()
+            .unwrap()
+            .retain(|device_id, _| connected.contains(device_id));
+        self.violations
+            .lock()
+            .unwrap()
+            .retain(|device_id, (_, violated_at)| {
+                connected.contains(device_id)
+                    || now.saturating_duration_since(*violated_at) < VIOLATIONS_TTL
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prune() {
+        let (connected, gone) = (DeviceId::new_v4(), DeviceId::new_v4());
+        let metrics = Metrics::default();
+        let now = Instant::now();
+
+        for device_id in [connected, gone] {
+            metrics.record(device_id, &Verdict::Drop(Violation::RateExceeded));
+            metrics.record_rtt(device_id, Duration::from_millis(5));
+        }
+
+        metrics.prune(&[connected], now);
+
+        assert!(metrics.rtt(&gone).is_none());
+        assert_eq!(metrics.violations().len(), 2);
+
+        metrics.prune(&[connected], now + VIOLATIONS_TTL);
+
+        assert_eq!(
+            metrics.violations().keys().collect::<Vec<_>>(),
+            vec![&connected]
+        );
+        assert!(metrics.rtt(&connected).is_some());
+    }
+}
@@ -0,0 +1,354 @@
+use crate::protocol::ExtServerMessage;
+use jojo_common::device::DeviceId;
+use log::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+// A flash sector of the esp32, the device writes each chunk as it arrives
+pub const CHUNK_SIZE: u32 = 4096;
+// Progress events per update, one for every 5%
+const PROGRESS_STEPS: u64 = 20;
+
+#[derive(Debug)]
+pub struct FirmwareImage {
+    pub version: String,
+    // Hex encoded
+    pub sha256: String,
+    bytes: Vec<u8>,
+}
+
+impl FirmwareImage {
+    pub fn new(version: impl Into<String>, bytes: Vec<u8>) -> Self {
+        FirmwareImage {
+            version: version.into(),
+            sha256: Sha256::digest(&bytes)
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+            bytes,
+        }
+    }
+
+    // Path is relative to the firmware directory and can't leave it, links included.
+    // The version defaults to the file name, images are usually named after it.
+    pub async fn load(dir: &Path, path: &Path, version: Option<String>) -> anyhow::Result<Self> {
+        let dir = tokio::fs::canonicalize(dir).await?;
+        let path = tokio::fs::canonicalize(dir.join(path))
+            .await
+            .map_err(|err| anyhow::anyhow!("firmware image {}: {}", path.display(), err))?;
+
+        if !path.starts_with(&dir) || !tokio::fs::metadata(&path).await?.is_file() {
+            anyhow::bail!("{} is not an image of {}", path.display(), dir.display());
+        }
+
+        let bytes = tokio::fs::read(&path).await?;
+        let version = version.unwrap_or_else(|| {
+            path.file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        });
+
+        if bytes.is_empty() {
+            anyhow::bail!("firmware image {} is empty", path.display());
+        }
+
+        Ok(FirmwareImage::new(version, bytes))
+    }
+
+    pub fn size(&self) -> u64 {
+        self.bytes.len() as u64
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FirmwareProgress {
+    Offered {
+        update_id: Uuid,
+        version: String,
+    },
+    Transferring {
+        update_id: Uuid,
+        offset: u64,
+        size: u64,
+    },
+    Completed(Uuid),
+    Failed {
+        update_id: Uuid,
+        reason: String,
+    },
+}
+
+// What the session does after a device message, a reply for the device and an event for the app
+#[derive(Debug, Default)]
+pub struct Step {
+    pub reply: Option<ExtServerMessage>,
+    pub progress: Option<FirmwareProgress>,
+}
+
+#[derive(Debug)]
+struct Update {
+    update_id: Uuid,
+    image: Arc<FirmwareImage>,
+    offset: u64,
+}
+
+impl Update {
+    fn offer(&self) -> ExtServerMessage {
+        ExtServerMessage::FirmwareOffer {
+            update_id: self.update_id,
+            version: self.image.version.clone(),
+            size: self.image.size(),
+            sha256: self.image.sha256.clone(),
+            chunk_size: CHUNK_SIZE,
+        }
+    }
+
+    fn chunk(&self) -> ExtServerMessage {
+        let start = self.offset as usize;
+        let end = (start + CHUNK_SIZE as usize).min(self.image.bytes.len());
+
+        ExtServerMessage::FirmwareChunk {
+            update_id: self.update_id,
+            offset: self.offset,
+            data: self.image.bytes[start..end].to_vec(),
+        }
+    }
+
+    fn step(&self, offset: u64) -> u64 {
+        offset * PROGRESS_STEPS / self.image.size()
+    }
+}
+
+// Updates in flight, they outlive the sessions so a device that reconnects resumes where it stopped
+#[derive(Debug, Clone, Default)]
+pub struct FirmwareUpdates {
+    updates: Arc<Mutex<HashMap<DeviceId, Update>>>,
+}
+
+impl FirmwareUpdates {
+    // Replaces any update of the device that was still running
+    pub fn start(&self, device_id: DeviceId, image: Arc<FirmwareImage>) -> ExtServerMessage {
+        let update = Update {
+            update_id: Uuid::new_v4(),
+            image,
+            offset: 0,
+        };
+        let offer = update.offer();
+
+        if let Some(previous) = self.updates.lock().unwrap().insert(device_id, update) {
+            warn!(
+                "[ota]: update {} of {} replaced",
+                previous.update_id, device_id
+            );
+        }
+
+        offer
+    }
+
+    // Offer again on reconnect, the device answers with the offset it already has
+    pub fn offer(&self, device_id: &DeviceId) -> Option<ExtServerMessage> {
+        self.updates
+            .lock()
+            .unwrap()
+            .get(device_id)
+            .map(Update::offer)
+    }
+
+    pub fn cancel(&self, device_id: &DeviceId) -> bool {
+        self.updates.lock().unwrap().remove(device_id).is_some()
+    }
+
+    pub fn ack(&self, device_id: &DeviceId, update_id: Uuid, offset: u64) -> Step {
+        let mut updates = self.updates.lock().unwrap();
+
+        let Some(update) = updates
+            .get_mut(device_id)
+            .filter(|update| update.update_id == update_id)
+        else {
+            warn!(
+                "[ota]: ack for unknown update {} of {}",
+                update_id, device_id
+            );
+            return Step::default();
+        };
+
+        let size = update.image.size();
+
+        if offset > size {
+            updates.remove(device_id);
+
+            return Step {
+                reply: None,
+                progress: Some(FirmwareProgress::Failed {
+                    update_id,
+                    reason: format!("device acked offset {} of {} bytes", offset, size),
+                }),
+            };
+        }
+
+        let progress =
+            (offset == 0 || update.step(offset) != update.step(update.offset)).then(|| {
+                FirmwareProgress::Transferring {
+                    update_id,
+                    offset,
+                    size,
+                }
+            });
+
+        update.offset = offset;
+
+        Step {
+            // Everything sent, the device answers with its digest
+            reply: (offset < size).then(|| update.chunk()),
+            progress,
+        }
+    }
+
+    // The update is over either way, a mismatch has to be started again
+    pub fn digest(&self, device_id: &DeviceId, update_id: Uuid, sha256: &str) -> Step {
+        let mut updates = self.updates.lock().unwrap();
+
+        if !updates
+            .get(device_id)
+            .is_some_and(|update| update.update_id == update_id)
+        {
+            warn!(
+                "[ota]: digest for unknown update {} of {}",
+                update_id, device_id
+            );
+            return Step::default();
+        }
+
+        let update = updates.remove(device_id).unwrap();
+
+        if !update.image.sha256.eq_ignore_ascii_case(sha256) {
+            return Step {
+                reply: None,
+                progress: Some(FirmwareProgress::Failed {
+                    update_id,
+                    reason: format!(
+                        "sha256 mismatch, expected {} got {}",
+                        update.image.sha256, sha256
+                    ),
+                }),
+            };
+        }
+
+        info!("[ota]: {} updated to {}", device_id, update.image.version);
+
+        Step {
+            reply: Some(ExtServerMessage::FirmwareFinalize(update_id)),
+            progress: Some(FirmwareProgress::Completed(update_id)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update_id(offer: &ExtServerMessage) -> Uuid {
+        match offer {
+            ExtServerMessage::FirmwareOffer { update_id, .. } => *update_id,
+            message => panic!("expected an offer, got {:?}", message),
+        }
+    }
+
+    #[test]
+    fn test_transfer() {
+        let device_id = DeviceId::new_v4();
+        let image = Arc::new(FirmwareImage::new(
+            "0.2.0",
+            vec![7; CHUNK_SIZE as usize + 10],
+        ));
+        let sha256 = image.sha256.clone();
+        let updates = FirmwareUpdates::default();
+
+        let update_id = update_id(&updates.start(device_id, image));
+
+        let step = updates.ack(&device_id, update_id, 0);
+
+        assert!(matches!(
+            step.reply,
+            Some(ExtServerMessage::FirmwareChunk { offset: 0, ref data, .. }) if data.len() == CHUNK_SIZE as usize
+        ));
+        assert!(step.progress.is_some());
+
+        // The device reconnects, the offer is the same and it resumes from its offset
+        assert_eq!(update_id(&updates.offer(&device_id).unwrap()), update_id);
+
+        let step = updates.ack(&device_id, update_id, CHUNK_SIZE as u64);
+
+        assert!(matches!(
+            step.reply,
+            Some(ExtServerMessage::FirmwareChunk { ref data, .. }) if data.len() == 10
+        ));
+        assert!(updates
+            .ack(&device_id, update_id, CHUNK_SIZE as u64 + 10)
+            .reply
+            .is_none());
+
+        let step = updates.digest(&device_id, update_id, &sha256.to_uppercase());
+
+        assert_eq!(
+            step.reply,
+            Some(ExtServerMessage::FirmwareFinalize(update_id))
+        );
+        assert_eq!(step.progress, Some(FirmwareProgress::Completed(update_id)));
+        assert!(updates.offer(&device_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_load() {
+        let dir = std::env::temp_dir().join(format!("jojo-firmware-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("0.3.0.bin"), [1, 2, 3]).unwrap();
+
+        let image = FirmwareImage::load(&dir, Path::new("0.3.0.bin"), None)
+            .await
+            .unwrap();
+
+        assert_eq!(image.version, "0.3.0");
+        assert!(FirmwareImage::load(&dir, &dir.join("0.3.0.bin"), None)
+            .await
+            .is_ok());
+        assert!(
+            FirmwareImage::load(&dir, Path::new("../../etc/passwd"), None)
+                .await
+                .is_err()
+        );
+        assert!(FirmwareImage::load(&dir, Path::new("/etc/passwd"), None)
+            .await
+            .is_err());
+        assert!(FirmwareImage::load(&dir, Path::new("."), None)
+            .await
+            .is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_digest_mismatch() {
+        let device_id = DeviceId::new_v4();
+        let updates = FirmwareUpdates::default();
+
+        let update_id = update_id(&updates.start(
+            device_id,
+            Arc::new(FirmwareImage::new("0.2.0", vec![1, 2, 3])),
+        ));
+
+        assert!(updates.ack(&device_id, Uuid::new_v4(), 0).reply.is_none());
+
+        let step = updates.digest(&device_id, update_id, "00");
+
+        assert!(step.reply.is_none());
+        assert!(matches!(
+            step.progress,
+            Some(FirmwareProgress::Failed { update_id: id, .. }) if id == update_id
+        ));
+    }
+}
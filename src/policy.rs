@@ -0,0 +1,525 @@
+use crate::command::CommandSpec;
+use crate::event::{EventSender, ServerEvent};
+use jojo_common::device::DeviceId;
+use log::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+const CONFIRM_TIMEOUT_MILLIS: u64 = 30_000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AllowedProgram {
+    Path(PathBuf),
+    // Hex encoded sha256 of the executable
+    Sha256(String),
+}
+
+// Args, env and cwd come from the device too, a spec only matches if the entry lets it set them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AllowedCommand {
+    pub program: AllowedProgram,
+    // The exact args, unless any_args is set
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub any_args: bool,
+    #[serde(default)]
+    pub allow_env: bool,
+    #[serde(default)]
+    pub allow_cwd: bool,
+}
+
+impl AllowedCommand {
+    pub fn new(program: AllowedProgram) -> Self {
+        AllowedCommand {
+            program,
+            args: Vec::new(),
+            any_args: false,
+            allow_env: false,
+            allow_cwd: false,
+        }
+    }
+
+    fn matches(
+        &self,
+        spec: &CommandSpec,
+        digests: &DigestCache,
+        digest: &mut Option<Option<String>>,
+    ) -> bool {
+        let path = Path::new(&spec.program);
+        let program = match &self.program {
+            AllowedProgram::Path(allowed) => {
+                allowed == path || allowed.canonicalize().is_ok_and(|allowed| allowed == path)
+            }
+            AllowedProgram::Sha256(hash) => digest
+                .get_or_insert_with(|| digests.digest(path))
+                .as_ref()
+                .is_some_and(|digest| digest.eq_ignore_ascii_case(hash)),
+        };
+
+        program
+            && (self.any_args || self.args == spec.args)
+            && (self.allow_env || spec.env.is_empty())
+            && (self.allow_cwd || spec.cwd.is_none())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UnknownCommand {
+    Allow,
+    Deny,
+    // Ask the app, the same spec of the same executable is not asked again
+    #[default]
+    Confirm,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandPolicy {
+    pub allowlist: Vec<AllowedCommand>,
+    pub unknown: UnknownCommand,
+    // Devices not listed here fall back to default_device_permission
+    pub device_permissions: HashMap<DeviceId, bool>,
+    pub default_device_permission: bool,
+}
+
+impl Default for CommandPolicy {
+    fn default() -> Self {
+        CommandPolicy {
+            allowlist: Vec::new(),
+            unknown: UnknownCommand::default(),
+            device_permissions: HashMap::new(),
+            default_device_permission: true,
+        }
+    }
+}
+
+impl CommandPolicy {
+    pub fn device_allowed(&self, device_id: &DeviceId) -> bool {
+        self.device_permissions
+            .get(device_id)
+            .copied()
+            .unwrap_or(self.default_device_permission)
+    }
+
+    // The program of the spec is the executable resolved by the backend
+    pub(crate) fn is_allowlisted(&self, spec: &CommandSpec, digests: &DigestCache) -> bool {
+        let mut digest: Option<Option<String>> = None;
+
+        self.allowlist
+            .iter()
+            .any(|entry| entry.matches(spec, digests, &mut digest))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Allow(&'static str),
+    Deny(&'static str),
+}
+
+#[derive(Clone)]
+pub struct CommandGuard {
+    policy: Arc<RwLock<CommandPolicy>>,
+    approved: Arc<Mutex<HashSet<String>>>,
+    pending: Arc<Mutex<HashMap<Uuid, std::sync::mpsc::Sender<bool>>>>,
+    digests: DigestCache,
+    confirm_timeout: Duration,
+    // Whether the app reading the server events answers confirmations, without it only /control clients can
+    app_confirms: bool,
+}
+
+impl Default for CommandGuard {
+    fn default() -> Self {
+        Self::new(CommandPolicy::default())
+    }
+}
+
+impl CommandGuard {
+    pub fn new(policy: CommandPolicy) -> Self {
+        CommandGuard {
+            policy: Arc::new(RwLock::new(policy)),
+            approved: Arc::new(Mutex::new(HashSet::new())),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            digests: DigestCache::default(),
+            confirm_timeout: Duration::from_millis(CONFIRM_TIMEOUT_MILLIS),
+            app_confirms: true,
+        }
+    }
+
+    pub fn with_confirm_timeout(mut self, confirm_timeout: Duration) -> Self {
+        self.confirm_timeout = confirm_timeout;
+        self
+    }
+
+    // For servers whose app only logs the events, a confirmation nobody can answer is denied right away instead
+    // of holding the actions of the device until it times out
+    pub fn with_app_confirmations(mut self, app_confirms: bool) -> Self {
+        self.app_confirms = app_confirms;
+        self
+    }
+
+    pub fn policy(&self) -> CommandPolicy {
+        self.policy.read().unwrap().clone()
+    }
+
+    pub fn set_policy(&self, policy: CommandPolicy) {
+        *self.policy.write().unwrap() = policy;
+    }
+
+    pub fn set_device_permission(&self, device_id: DeviceId, allowed: bool) {
+        self.policy
+            .write()
+            .unwrap()
+            .device_permissions
+            .insert(device_id, allowed);
+    }
+
+    // Answer a ServerEvent::CommandConfirmation, returns false if the request already expired
+    pub fn confirm(&self, request_id: Uuid, allow: bool) -> bool {
+        match self.pending.lock().unwrap().remove(&request_id) {
+            Some(reply_tx) => reply_tx.send(allow).is_ok(),
+            None => false,
+        }
+    }
+
+    // Blocking, it can wait for the app to confirm the command. The spec comes resolved by the backend.
+    pub fn authorize(
+        &self,
+        device_id: DeviceId,
+        spec: &CommandSpec,
+        server_event_tx: &EventSender,
+    ) -> Decision {
+        let decision = self.decide(device_id, spec, server_event_tx);
+
+        match decision {
+            Decision::Allow(reason) => info!(
+                "[command_guard]: allow {} from {}, reason: {}",
+                spec.program, device_id, reason
+            ),
+            Decision::Deny(reason) => warn!(
+                "[command_guard]: deny {} from {}, reason: {}",
+                spec.program, device_id, reason
+            ),
+        }
+
+        decision
+    }
+
+    fn decide(
+        &self,
+        device_id: DeviceId,
+        spec: &CommandSpec,
+        server_event_tx: &EventSender,
+    ) -> Decision {
+        let policy = self.policy();
+
+        if !policy.device_allowed(&device_id) {
+            return Decision::Deny("device not allowed to run commands");
+        }
+
+        if policy.is_allowlisted(spec, &self.digests) {
+            return Decision::Allow("allowlisted");
+        }
+
+        match policy.unknown {
+            UnknownCommand::Allow => Decision::Allow("unknown commands allowed"),
+            UnknownCommand::Deny => Decision::Deny("not allowlisted"),
+            UnknownCommand::Confirm => {
+                // Hashed before taking the lock, the other authorizations don't wait on it
+                let key = approval_key(spec, &self.digests);
+
+                match self.approved.lock().unwrap().contains(&key) {
+                    true => Decision::Allow("confirmed before"),
+                    false => self.ask(device_id, spec, key, server_event_tx),
+                }
+            }
+        }
+    }
+
+    fn ask(
+        &self,
+        device_id: DeviceId,
+        spec: &CommandSpec,
+        key: String,
+        server_event_tx: &EventSender,
+    ) -> Decision {
+        if !self.app_confirms && server_event_tx.control_subscribers() == 0 {
+            return Decision::Deny("nobody listening for confirmations");
+        }
+
+        let request_id = Uuid::new_v4();
+        let (reply_tx, reply_rx) = std::sync::mpsc::channel::<bool>();
+
+        self.pending.lock().unwrap().insert(request_id, reply_tx);
+
+        if server_event_tx
+            .blocking_send(ServerEvent::CommandConfirmation(
+                request_id,
+                device_id,
+                spec.clone(),
+            ))
+            .is_err()
+        {
+            self.pending.lock().unwrap().remove(&request_id);
+            return Decision::Deny("app not listening for confirmations");
+        }
+
+        let answer = reply_rx.recv_timeout(self.confirm_timeout);
+        self.pending.lock().unwrap().remove(&request_id);
+
+        match answer {
+            Ok(true) => {
+                self.approved.lock().unwrap().insert(key);
+                Decision::Allow("confirmed by app")
+            }
+            Ok(false) => Decision::Deny("rejected by app"),
+            Err(_) => Decision::Deny("confirmation timed out"),
+        }
+    }
+}
+
+// Everything that changes what runs, an executable replaced since the confirmation is asked again
+fn approval_key(spec: &CommandSpec, digests: &DigestCache) -> String {
+    let env: BTreeMap<&String, &String> = spec.env.iter().collect();
+
+    serde_json::json!([
+        spec.program,
+        spec.args,
+        env,
+        spec.cwd,
+        digests.digest(Path::new(&spec.program)),
+    ])
+    .to_string()
+}
+
+// Executables are hashed again only when their modification time or length changes
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DigestCache {
+    digests: Arc<Mutex<HashMap<PathBuf, (SystemTime, u64, String)>>>,
+}
+
+impl DigestCache {
+    fn digest(&self, path: &Path) -> Option<String> {
+        let metadata = std::fs::metadata(path).ok()?;
+        let Ok(modified) = metadata.modified() else {
+            return sha256_file(path);
+        };
+        let stamp = (modified, metadata.len());
+
+        if let Some((modified, len, digest)) = self.digests.lock().unwrap().get(path) {
+            if (*modified, *len) == stamp {
+                return Some(digest.clone());
+            }
+        }
+
+        // Not under the lock, a large executable doesn't hold up the others
+        let digest = sha256_file(path)?;
+
+        self.digests
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), (stamp.0, stamp.1, digest.clone()));
+
+        Some(digest)
+    }
+}
+
+fn sha256_file(path: &Path) -> Option<String> {
+    let bytes = std::fs::read(path).ok()?;
+
+    Some(
+        Sha256::digest(bytes)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_permission() {
+        let device_id = DeviceId::new_v4();
+        let (tx, _rx) = tokio::sync::mpsc::channel::<ServerEvent>(32);
+        let tx = EventSender::from(tx);
+
+        let guard = CommandGuard::new(CommandPolicy {
+            unknown: UnknownCommand::Allow,
+            ..Default::default()
+        });
+
+        assert_eq!(
+            guard.authorize(device_id, &CommandSpec::new("app"), &tx),
+            Decision::Allow("unknown commands allowed")
+        );
+
+        guard.set_device_permission(device_id, false);
+
+        assert_eq!(
+            guard.authorize(device_id, &CommandSpec::new("app"), &tx),
+            Decision::Deny("device not allowed to run commands")
+        );
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let device_id = DeviceId::new_v4();
+        let (tx, _rx) = tokio::sync::mpsc::channel::<ServerEvent>(32);
+        let tx = EventSender::from(tx);
+        let file = std::env::temp_dir().join(format!("jojo-policy-{}", Uuid::new_v4()));
+        std::fs::write(&file, b"jojo").unwrap();
+
+        let guard = CommandGuard::new(CommandPolicy {
+            allowlist: vec![
+                AllowedCommand {
+                    args: vec!["--check".to_string()],
+                    ..AllowedCommand::new(AllowedProgram::Path(PathBuf::from("/usr/bin/allowed")))
+                },
+                AllowedCommand {
+                    any_args: true,
+                    ..AllowedCommand::new(AllowedProgram::Sha256(
+                        "54af2a2960e582263c45971cdd40da4ae31ede1db5395629d910f056479de12d"
+                            .to_string(),
+                    ))
+                },
+            ],
+            unknown: UnknownCommand::Deny,
+            ..Default::default()
+        });
+        let spec = |program: &str, args: &[&str]| CommandSpec {
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            ..CommandSpec::new(program)
+        };
+        let hashed = spec(&file.to_string_lossy(), &["-c", "anything"]);
+
+        let allowed = guard.authorize(device_id, &spec("/usr/bin/allowed", &["--check"]), &tx);
+        let other_args = guard.authorize(device_id, &spec("/usr/bin/allowed", &["-c"]), &tx);
+        let denied = guard.authorize(device_id, &spec("/usr/bin/other", &[]), &tx);
+        let hashed_any_args = guard.authorize(device_id, &hashed, &tx);
+        // Env and cwd are only allowed when the entry says so
+        let with_env = guard.authorize(
+            device_id,
+            &CommandSpec {
+                env: HashMap::from([("LD_PRELOAD".to_string(), "/tmp/evil.so".to_string())]),
+                ..hashed.clone()
+            },
+            &tx,
+        );
+        let with_cwd = guard.authorize(
+            device_id,
+            &CommandSpec {
+                cwd: Some(std::env::temp_dir()),
+                ..hashed
+            },
+            &tx,
+        );
+
+        std::fs::remove_file(&file).unwrap();
+
+        assert_eq!(allowed, Decision::Allow("allowlisted"));
+        assert_eq!(other_args, Decision::Deny("not allowlisted"));
+        assert_eq!(denied, Decision::Deny("not allowlisted"));
+        assert_eq!(hashed_any_args, Decision::Allow("allowlisted"));
+        assert_eq!(with_env, Decision::Deny("not allowlisted"));
+        assert_eq!(with_cwd, Decision::Deny("not allowlisted"));
+    }
+
+    #[test]
+    fn test_digest_cache() {
+        let file = std::env::temp_dir().join(format!("jojo-digest-{}", Uuid::new_v4()));
+        std::fs::write(&file, b"jojo").unwrap();
+        let digests = DigestCache::default();
+
+        let first = digests.digest(&file);
+        assert_eq!(first, sha256_file(&file));
+        assert_eq!(digests.digest(&file), first);
+
+        // Another length is hashed again even if the mtime didn't move
+        std::fs::write(&file, b"jojo jojo").unwrap();
+        let second = digests.digest(&file);
+
+        std::fs::remove_file(&file).unwrap();
+
+        assert_ne!(second, first);
+        assert!(digests.digest(&file).is_none());
+    }
+
+    #[test]
+    fn test_confirmation() {
+        let device_id = DeviceId::new_v4();
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<ServerEvent>(32);
+        let tx = EventSender::from(tx);
+        let guard = CommandGuard::default().with_confirm_timeout(Duration::from_millis(1_000));
+        let guard_clone = guard.clone();
+
+        // Answers the first confirmation only, the receiver is kept so later ones wait for an answer
+        let app = std::thread::spawn(move || {
+            let confirmed = match rx.blocking_recv() {
+                Some(ServerEvent::CommandConfirmation(request_id, _, _)) => {
+                    guard_clone.confirm(request_id, true)
+                }
+                _ => false,
+            };
+
+            (confirmed, rx)
+        });
+
+        let first = guard.authorize(device_id, &CommandSpec::new("/bin/sh"), &tx);
+        let second = guard.authorize(device_id, &CommandSpec::new("/bin/sh"), &tx);
+        // Confirming sh doesn't confirm it with other args
+        let other_args = guard.authorize(
+            device_id,
+            &CommandSpec {
+                args: vec!["-c".to_string(), "rm -rf ~".to_string()],
+                ..CommandSpec::new("/bin/sh")
+            },
+            &tx,
+        );
+
+        assert!(app.join().unwrap().0);
+        assert_eq!(first, Decision::Allow("confirmed by app"));
+        assert_eq!(second, Decision::Allow("confirmed before"));
+        assert_eq!(other_args, Decision::Deny("confirmation timed out"));
+    }
+
+    #[test]
+    fn test_confirmation_timeout() {
+        let (tx, _rx) = tokio::sync::mpsc::channel::<ServerEvent>(32);
+        let tx = EventSender::from(tx);
+        let guard = CommandGuard::default().with_confirm_timeout(Duration::from_millis(10));
+
+        assert_eq!(
+            guard.authorize(DeviceId::new_v4(), &CommandSpec::new("app"), &tx),
+            Decision::Deny("confirmation timed out")
+        );
+    }
+
+    #[test]
+    fn test_confirmation_without_listener() {
+        let (tx, _rx) = tokio::sync::mpsc::channel::<ServerEvent>(32);
+        let tx = EventSender::from(tx);
+        let guard = CommandGuard::default()
+            .with_confirm_timeout(Duration::from_millis(10))
+            .with_app_confirmations(false);
+
+        assert_eq!(
+            guard.authorize(DeviceId::new_v4(), &CommandSpec::new("app"), &tx),
+            Decision::Deny("nobody listening for confirmations")
+        );
+
+        // A /control client can answer
+        let _control = tx.subscribe();
+
+        assert_eq!(
+            guard.authorize(DeviceId::new_v4(), &CommandSpec::new("app"), &tx),
+            Decision::Deny("confirmation timed out")
+        );
+    }
+}
@@ -0,0 +1,465 @@
+use crate::registry::ButtonId;
+use crate::telemetry::Telemetry;
+use bincode::Options;
+use jojo_common::message::{ClientMessage, ServerMessage};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+// Clients without a hello are version 1, they only speak ClientMessage and ServerMessage
+pub const PROTOCOL_VERSION: u16 = 2;
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+// Messages that jojo_common::message::ClientMessage doesn't know about travel with this tag in front.
+// A ClientMessage starts with its variant index, so the tag can never be mistaken for one.
+pub const EXTENSION_TAG: u32 = 0x4A4F_4A4F;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExtClientMessage {
+    // Raw button state, the server resolves the mapping from the registry
+    ButtonEvent(ButtonId, bool),
+    // Battery, signal and firmware state, devices send it every few seconds
+    Telemetry(Telemetry),
+    // Next firmware offset the device wants, the first ack after an offer says how much it already has
+    FirmwareAck(Uuid, u64),
+    // Hex encoded sha256 of the image the device wrote
+    FirmwareDigest(Uuid, String),
+    // First message of a client that speaks the extensions
+    Hello(Hello),
+}
+
+// Groups of extension messages a client can opt into
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Capability {
+    ButtonEvents,
+    Telemetry,
+    Firmware,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 3] = [
+        Capability::ButtonEvents,
+        Capability::Telemetry,
+        Capability::Firmware,
+    ];
+}
+
+impl ExtClientMessage {
+    // The hello is the only one a client can send before it negotiated anything
+    pub fn capability(&self) -> Option<Capability> {
+        match self {
+            ExtClientMessage::ButtonEvent(..) => Some(Capability::ButtonEvents),
+            ExtClientMessage::Telemetry(_) => Some(Capability::Telemetry),
+            ExtClientMessage::FirmwareAck(..) | ExtClientMessage::FirmwareDigest(..) => {
+                Some(Capability::Firmware)
+            }
+            ExtClientMessage::Hello(_) => None,
+        }
+    }
+}
+
+// Picked with the websocket subprotocol or the hello. Frames from the server use it, text frames from clients
+// are always json and binary ones use it unless it's json, then they are bincode
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Encoding {
+    #[default]
+    Bincode,
+    // Text frames
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Encoding {
+    pub const SUPPORTED: [Encoding; 4] = [
+        Encoding::Bincode,
+        Encoding::Json,
+        Encoding::MessagePack,
+        Encoding::Cbor,
+    ];
+
+    pub fn subprotocol(&self) -> &'static str {
+        match self {
+            Encoding::Bincode => "jojo.bincode",
+            Encoding::Json => "jojo.json",
+            Encoding::MessagePack => "jojo.msgpack",
+            Encoding::Cbor => "jojo.cbor",
+        }
+    }
+
+    // First one the client asked for in a Sec-WebSocket-Protocol header
+    pub fn from_subprotocols(header: &str) -> Option<Self> {
+        header.split(',').find_map(|subprotocol| {
+            Encoding::SUPPORTED
+                .into_iter()
+                .find(|encoding| encoding.subprotocol() == subprotocol.trim())
+        })
+    }
+
+    pub fn is_text(&self) -> bool {
+        *self == Encoding::Json
+    }
+
+    // Frames from clients
+    fn inbound(&self, text: bool) -> Encoding {
+        match (text, self) {
+            (true, _) => Encoding::Json,
+            (false, Encoding::Json) => Encoding::Bincode,
+            (false, encoding) => *encoding,
+        }
+    }
+
+    fn to_vec<T: Serialize>(&self, value: &T) -> anyhow::Result<Vec<u8>> {
+        Ok(match self {
+            Encoding::Bincode => bincode::serialize(value)?,
+            Encoding::Json => serde_json::to_vec(value)?,
+            Encoding::MessagePack => rmp_serde::to_vec_named(value)?,
+            Encoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes)?;
+                bytes
+            }
+        })
+    }
+
+    fn from_slice<T: DeserializeOwned>(&self, bytes: &[u8]) -> anyhow::Result<T> {
+        Ok(match self {
+            Encoding::Bincode => deserialize_bincode(bytes)?,
+            Encoding::Json => serde_json::from_slice(bytes)?,
+            Encoding::MessagePack => rmp_serde::from_slice(bytes)?,
+            Encoding::Cbor => ciborium::from_reader(bytes)?,
+        })
+    }
+
+    // Json tells the messages apart by their names, the other encodings need the extension tag
+    fn decode<M: DeserializeOwned, X: DeserializeOwned>(
+        &self,
+        bytes: &[u8],
+    ) -> anyhow::Result<Result<M, X>> {
+        match self.from_slice::<M>(bytes) {
+            Ok(message) => Ok(Ok(message)),
+            Err(err) if self.is_text() => self.from_slice::<X>(bytes).map(Err).map_err(|_| err),
+            Err(err) => match self.from_slice::<(u32, X)>(bytes) {
+                Ok((EXTENSION_TAG, message)) => Ok(Err(message)),
+                _ => Err(err),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hello {
+    pub protocol_version: u16,
+    pub capabilities: Vec<Capability>,
+    // In order of preference
+    pub encodings: Vec<Encoding>,
+}
+
+// What the server and a client agreed on, the server answers a hello with it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Peer {
+    pub protocol_version: u16,
+    pub capabilities: Vec<Capability>,
+    pub encoding: Encoding,
+}
+
+impl Default for Peer {
+    // A client that never said hello
+    fn default() -> Self {
+        Peer {
+            protocol_version: MIN_PROTOCOL_VERSION,
+            capabilities: Vec::new(),
+            encoding: Encoding::Bincode,
+        }
+    }
+}
+
+impl Peer {
+    // The error is the close reason sent to the client. An encoding picked with the subprotocol can't change
+    // anymore, the hello has to list it
+    pub fn negotiate(hello: &Hello, subprotocol: Option<Encoding>) -> Result<Self, String> {
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&hello.protocol_version) {
+            return Err(format!(
+                "protocol version {} is not supported, the server speaks {} to {}",
+                hello.protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ));
+        }
+
+        let Some(encoding) = hello.encodings.iter().find(|encoding| match subprotocol {
+            Some(subprotocol) => **encoding == subprotocol,
+            None => Encoding::SUPPORTED.contains(encoding),
+        }) else {
+            return Err(match subprotocol {
+                Some(subprotocol) => format!(
+                    "{:?} isn't in {:?}, the subprotocol already picked it",
+                    subprotocol, hello.encodings
+                ),
+                None => format!(
+                    "no supported encoding in {:?}, the server speaks {:?}",
+                    hello.encodings,
+                    Encoding::SUPPORTED
+                ),
+            });
+        };
+
+        Ok(Peer {
+            protocol_version: hello.protocol_version,
+            capabilities: Capability::ALL
+                .into_iter()
+                .filter(|capability| hello.capabilities.contains(capability))
+                .collect(),
+            encoding: *encoding,
+        })
+    }
+
+    pub fn sends(&self, message: &ExtClientMessage) -> bool {
+        message
+            .capability()
+            .map_or(true, |capability| self.capabilities.contains(&capability))
+    }
+
+    // Messages the client wouldn't understand are dropped instead of sent
+    pub fn accepts(&self, message: &Outbound) -> bool {
+        match message {
+            Outbound::Server(_) => true,
+            Outbound::Extension(ExtServerMessage::Welcome(_)) => true,
+            Outbound::Extension(
+                ExtServerMessage::FirmwareOffer { .. }
+                | ExtServerMessage::FirmwareChunk { .. }
+                | ExtServerMessage::FirmwareFinalize(_),
+            ) => self.capabilities.contains(&Capability::Firmware),
+        }
+    }
+}
+
+// Messages for the device that jojo_common::message::ServerMessage doesn't know about, same tag as above
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExtServerMessage {
+    FirmwareOffer {
+        update_id: Uuid,
+        version: String,
+        size: u64,
+        sha256: String,
+        chunk_size: u32,
+    },
+    FirmwareChunk {
+        update_id: Uuid,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    // The digest matched, the device can boot the new image
+    FirmwareFinalize(Uuid),
+    Welcome(Peer),
+}
+
+// Everything a session sends to its device
+#[derive(Debug, Clone)]
+pub enum Outbound {
+    Server(ServerMessage),
+    Extension(ExtServerMessage),
+}
+
+impl From<ServerMessage> for Outbound {
+    fn from(message: ServerMessage) -> Self {
+        Outbound::Server(message)
+    }
+}
+
+impl From<ExtServerMessage> for Outbound {
+    fn from(message: ExtServerMessage) -> Self {
+        Outbound::Extension(message)
+    }
+}
+
+impl Outbound {
+    // Json is a text frame, the rest are binary
+    pub fn encode_as(&self, encoding: Encoding) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Outbound::Server(message) => encoding.to_vec(message),
+            Outbound::Extension(message) if encoding.is_text() => encoding.to_vec(message),
+            Outbound::Extension(message) => encoding.to_vec(&(EXTENSION_TAG, message)),
+        }
+    }
+
+    pub fn decode_as(encoding: Encoding, bytes: &[u8]) -> anyhow::Result<Self> {
+        Ok(
+            match encoding.decode::<ServerMessage, ExtServerMessage>(bytes)? {
+                Ok(message) => Outbound::Server(message),
+                Err(message) => Outbound::Extension(message),
+            },
+        )
+    }
+}
+
+pub enum Inbound {
+    Client(ClientMessage),
+    Extension(ExtClientMessage),
+}
+
+// Same as bincode::deserialize, but a length prefix can't claim more bytes than the frame has
+fn deserialize_bincode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, bincode::Error> {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(bytes.len() as u64)
+        .deserialize(bytes)
+}
+
+// Frames of a connection that negotiated an encoding
+pub fn decode_frame(encoding: Encoding, bytes: &[u8], text: bool) -> anyhow::Result<Inbound> {
+    Ok(
+        match encoding
+            .inbound(text)
+            .decode::<ClientMessage, ExtClientMessage>(bytes)?
+        {
+            Ok(client_message) => Inbound::Client(client_message),
+            Err(message) => Inbound::Extension(message),
+        },
+    )
+}
+
+pub fn encode_client_as(encoding: Encoding, message: &ClientMessage) -> anyhow::Result<Vec<u8>> {
+    encoding.to_vec(message)
+}
+
+pub fn encode_extension_as(
+    encoding: Encoding,
+    message: &ExtClientMessage,
+) -> anyhow::Result<Vec<u8>> {
+    match encoding.is_text() {
+        true => encoding.to_vec(message),
+        false => encoding.to_vec(&(EXTENSION_TAG, message)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_extension() {
+        let message = ExtClientMessage::ButtonEvent(ButtonId::new_v4(), true);
+
+        let binary = encode_extension_as(Encoding::Bincode, &message).unwrap();
+        let text = encode_extension_as(Encoding::Json, &message).unwrap();
+
+        assert!(matches!(
+            decode_frame(Encoding::Bincode, &binary, false),
+            Ok(Inbound::Extension(decoded)) if decoded == message
+        ));
+        // Text frames are json whatever the connection negotiated
+        assert!(matches!(
+            decode_frame(Encoding::Cbor, &text, true),
+            Ok(Inbound::Extension(decoded)) if decoded == message
+        ));
+        assert!(decode_frame(Encoding::Bincode, &[0xff; 8], false).is_err());
+        // A string claiming far more bytes than the frame holds
+        assert!(deserialize_bincode::<String>(&u64::MAX.to_le_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let hello = Hello {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: vec![Capability::Telemetry],
+            encodings: vec![Encoding::Json, Encoding::Bincode],
+        };
+        let peer = Peer::negotiate(&hello, None).unwrap();
+
+        assert_eq!(peer.capabilities, vec![Capability::Telemetry]);
+        assert_eq!(peer.encoding, Encoding::Json);
+        assert!(peer.sends(&ExtClientMessage::Hello(hello.clone())));
+        assert!(!peer.sends(&ExtClientMessage::FirmwareAck(Uuid::new_v4(), 0)));
+        assert!(!peer.sends(&ExtClientMessage::ButtonEvent(ButtonId::new_v4(), true)));
+        assert!(
+            !peer.accepts(&Outbound::from(ExtServerMessage::FirmwareFinalize(
+                Uuid::new_v4()
+            )))
+        );
+        assert!(
+            !Peer::default().accepts(&Outbound::from(ExtServerMessage::FirmwareFinalize(
+                Uuid::new_v4()
+            )))
+        );
+
+        // The subprotocol wins over the order of the hello
+        assert_eq!(
+            Peer::negotiate(&hello, Some(Encoding::Bincode))
+                .unwrap()
+                .encoding,
+            Encoding::Bincode
+        );
+        assert!(Peer::negotiate(&hello, Some(Encoding::Cbor)).is_err());
+
+        assert!(Peer::negotiate(
+            &Hello {
+                protocol_version: PROTOCOL_VERSION + 1,
+                ..hello.clone()
+            },
+            None
+        )
+        .is_err());
+        assert!(Peer::negotiate(
+            &Hello {
+                encodings: Vec::new(),
+                ..hello
+            },
+            None
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_encodings() {
+        let device_id = Uuid::new_v4();
+        let message = ExtClientMessage::ButtonEvent(ButtonId::new_v4(), false);
+
+        assert_eq!(
+            Encoding::from_subprotocols("chat, jojo.cbor, jojo.json"),
+            Some(Encoding::Cbor)
+        );
+        assert_eq!(Encoding::from_subprotocols("chat"), None);
+
+        for encoding in Encoding::SUPPORTED {
+            let bytes = encode_extension_as(encoding, &message).unwrap();
+
+            assert!(matches!(
+                decode_frame(encoding, &bytes, encoding.is_text()),
+                Ok(Inbound::Extension(decoded)) if decoded == message
+            ));
+
+            let bytes = Outbound::from(ServerMessage::RestartDevice(device_id))
+                .encode_as(encoding)
+                .unwrap();
+
+            assert!(matches!(
+                Outbound::decode_as(encoding, &bytes),
+                Ok(Outbound::Server(ServerMessage::RestartDevice(id))) if id == device_id
+            ));
+        }
+    }
+
+    #[test]
+    fn test_outbound() {
+        let device_id = Uuid::new_v4();
+        let update_id = Uuid::new_v4();
+
+        for encoding in Encoding::SUPPORTED {
+            let server = Outbound::from(ServerMessage::RestartDevice(device_id))
+                .encode_as(encoding)
+                .unwrap();
+            let extension = Outbound::from(ExtServerMessage::FirmwareFinalize(update_id))
+                .encode_as(encoding)
+                .unwrap();
+
+            assert!(matches!(
+                Outbound::decode_as(encoding, &server),
+                Ok(Outbound::Server(ServerMessage::RestartDevice(id))) if id == device_id
+            ));
+            assert!(matches!(
+                Outbound::decode_as(encoding, &extension),
+                Ok(Outbound::Extension(ExtServerMessage::FirmwareFinalize(id))) if id == update_id
+            ));
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use crate::registry::ButtonId;
+use jojo_common::device::DeviceId;
+use jojo_common::message::ClientMessage;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RecordFormat {
+    // One json object per line, easy to read and to edit by hand
+    Jsonl,
+    Bincode,
+}
+
+impl RecordFormat {
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("jsonl") | Some("json") => RecordFormat::Jsonl,
+            _ => RecordFormat::Bincode,
+        }
+    }
+}
+
+// What a device sent that drives the host, button events mapped by the server included
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RecordedInput {
+    Client(ClientMessage),
+    // ExtClientMessage::ButtonEvent, replayed through the mappings of the device
+    ButtonEvent(ButtonId, bool),
+}
+
+// Borrowed twin of RecordedInput, ClientMessage isn't Clone
+#[derive(Debug, Clone, Copy, Serialize)]
+pub enum InputRef<'a> {
+    Client(&'a ClientMessage),
+    ButtonEvent(ButtonId, bool),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecordedMessage {
+    pub device_id: DeviceId,
+    // Monotonic time since the recording started
+    pub elapsed_micros: u64,
+    pub message: RecordedInput,
+}
+
+pub struct Recorder {
+    device_id: DeviceId,
+    path: PathBuf,
+    format: RecordFormat,
+    writer: BufWriter<File>,
+    started: Instant,
+    // Last time already in the file, a reconnect appends after it instead of starting over
+    offset_micros: u64,
+}
+
+impl Recorder {
+    // The format follows the extension, so the file can be read back without being told. An existing recording
+    // is appended to, the time between the two sessions is left out of the replay
+    pub fn create(device_id: DeviceId, path: &Path) -> anyhow::Result<Self> {
+        let offset_micros = match path.exists() {
+            true => read_recording(path)?
+                .last()
+                .map_or(0, |recorded| recorded.elapsed_micros),
+            false => 0,
+        };
+
+        Ok(Recorder {
+            device_id,
+            path: path.to_path_buf(),
+            format: RecordFormat::from_path(path),
+            writer: BufWriter::new(OpenOptions::new().create(true).append(true).open(path)?),
+            started: Instant::now(),
+            offset_micros,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&mut self, message: InputRef<'_>) -> anyhow::Result<()> {
+        // Borrowed twin of RecordedMessage
+        #[derive(Serialize)]
+        struct Recorded<'a> {
+            device_id: DeviceId,
+            elapsed_micros: u64,
+            message: InputRef<'a>,
+        }
+
+        let recorded = Recorded {
+            device_id: self.device_id,
+            elapsed_micros: self.offset_micros + self.started.elapsed().as_micros() as u64,
+            message,
+        };
+
+        match self.format {
+            RecordFormat::Jsonl => {
+                serde_json::to_writer(&mut self.writer, &recorded)?;
+                self.writer.write_all(b"\n")?;
+            }
+            RecordFormat::Bincode => bincode::serialize_into(&mut self.writer, &recorded)?,
+        }
+
+        // A recording is mostly wanted after something went wrong, don't keep it in memory
+        self.writer.flush()?;
+
+        Ok(())
+    }
+}
+
+pub fn read_recording(path: &Path) -> anyhow::Result<Vec<RecordedMessage>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut recording = Vec::new();
+
+    match RecordFormat::from_path(path) {
+        RecordFormat::Jsonl => {
+            for line in reader.lines() {
+                let line = line?;
+
+                if !line.trim().is_empty() {
+                    recording.push(serde_json::from_str(&line)?);
+                }
+            }
+        }
+        RecordFormat::Bincode => {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes)?;
+
+            let mut remaining = bytes.as_slice();
+
+            while !remaining.is_empty() {
+                recording.push(bincode::deserialize_from(&mut remaining)?);
+            }
+        }
+    }
+
+    Ok(recording)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jojo_common::button::ButtonAction;
+    use jojo_common::keyboard::KeyboardButton;
+
+    fn roundtrip(extension: &str) {
+        let device_id = DeviceId::new_v4();
+        let path = std::env::temp_dir().join(format!("{}.{}", DeviceId::new_v4(), extension));
+        let message = || {
+            ClientMessage::ButtonActions(vec![ButtonAction::KeyboardButton(
+                KeyboardButton::Sequence("jojo".to_string()),
+            )])
+        };
+
+        let button_id = ButtonId::new_v4();
+        let mut recorder = Recorder::create(device_id, &path).unwrap();
+
+        recorder.record(InputRef::Client(&message())).unwrap();
+        recorder
+            .record(InputRef::ButtonEvent(button_id, true))
+            .unwrap();
+        drop(recorder);
+
+        // A reconnect opens the same file again
+        let mut recorder = Recorder::create(device_id, &path).unwrap();
+
+        recorder.record(InputRef::Client(&message())).unwrap();
+        drop(recorder);
+
+        let recording = read_recording(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(recording.len(), 3);
+        assert!(matches!(
+            recording[1].message,
+            RecordedInput::ButtonEvent(id, true) if id == button_id
+        ));
+        assert!(recording
+            .iter()
+            .all(|recorded| recorded.device_id == device_id));
+        assert!(recording
+            .windows(2)
+            .all(|pair| pair[0].elapsed_micros <= pair[1].elapsed_micros));
+    }
+
+    #[test]
+    fn test_jsonl_roundtrip() {
+        roundtrip("jsonl");
+    }
+
+    #[test]
+    fn test_bincode_roundtrip() {
+        roundtrip("bin");
+    }
+}
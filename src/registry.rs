@@ -0,0 +1,329 @@
+use crate::calibration::{AxisCalibration, AxisCapture, AxisKey};
+use crate::filter::FilterConfig;
+use crate::repeat::RepeatMode;
+use crate::routing::{AxisRoute, HatRoute};
+use jojo_common::button::ButtonAction;
+use jojo_common::device::DeviceId;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+pub type ButtonId = Uuid;
+
+pub const DEFAULT_PROFILE: &str = "default";
+
+// Actions a mapping can hold, besides the ones the drivers run the server handles profiles and layers itself
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum MappedAction {
+    Action(ButtonAction),
+    SwitchProfile(String),
+    // Active only while the button stays pressed
+    MomentaryLayer(String),
+    ToggleLayer(String),
+    PushLayer(String),
+    PopLayer,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ButtonMapping {
+    pub press: Vec<MappedAction>,
+    #[serde(default)]
+    pub release: Vec<MappedAction>,
+    #[serde(default)]
+    pub repeat: RepeatMode,
+}
+
+impl From<Vec<ButtonAction>> for ButtonMapping {
+    fn from(press: Vec<ButtonAction>) -> Self {
+        ButtonMapping {
+            press: press.into_iter().map(MappedAction::Action).collect(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Layer {
+    pub buttons: HashMap<ButtonId, ButtonMapping>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    pub buttons: HashMap<ButtonId, ButtonMapping>,
+    #[serde(default)]
+    pub layers: HashMap<String, Layer>,
+}
+
+// Server side configuration of a device, it outlives the connection so it can be edited while the device is offline
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceConfig {
+    pub profiles: HashMap<String, Profile>,
+    pub default_profile: String,
+    // Virtual gamepad slot reserved for the device, the first free one is used otherwise
+    #[serde(default)]
+    pub gamepad_slot: Option<u8>,
+    #[serde(default)]
+    pub axes: HashMap<AxisKey, AxisCalibration>,
+    #[serde(default)]
+    pub filters: HashMap<AxisKey, FilterConfig>,
+    // Axes and hats without a route drive the virtual gamepad
+    #[serde(default)]
+    pub routes: HashMap<AxisKey, AxisRoute>,
+    #[serde(default)]
+    pub hats: HashMap<AxisKey, HatRoute>,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        DeviceConfig {
+            profiles: HashMap::from([(DEFAULT_PROFILE.to_string(), Profile::default())]),
+            default_profile: DEFAULT_PROFILE.to_string(),
+            gamepad_slot: None,
+            axes: HashMap::new(),
+            filters: HashMap::new(),
+            routes: HashMap::new(),
+            hats: HashMap::new(),
+        }
+    }
+}
+
+impl DeviceConfig {
+    // Layers are searched from the last one pushed down to the profile buttons
+    pub fn resolve(
+        &self,
+        profile: &str,
+        layers: &[String],
+        button_id: &ButtonId,
+    ) -> Option<&ButtonMapping> {
+        let profile = self.profiles.get(profile)?;
+
+        layers
+            .iter()
+            .rev()
+            .find_map(|layer| {
+                profile
+                    .layers
+                    .get(layer)
+                    .and_then(|layer| layer.buttons.get(button_id))
+            })
+            .or_else(|| profile.buttons.get(button_id))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DeviceRegistry {
+    configs: HashMap<DeviceId, DeviceConfig>,
+    // Devices the app is calibrating right now
+    captures: HashMap<DeviceId, HashMap<AxisKey, AxisCapture>>,
+    // Files the decoded messages of each device are recorded to
+    recordings: HashMap<DeviceId, PathBuf>,
+}
+
+impl DeviceRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, device_id: &DeviceId) -> Option<&DeviceConfig> {
+        self.configs.get(device_id)
+    }
+
+    pub fn entry(&mut self, device_id: DeviceId) -> &mut DeviceConfig {
+        self.configs.entry(device_id).or_default()
+    }
+
+    // Same payload as ServerMessage::UpdateDevice, it replaces the buttons of the default profile
+    pub fn set_buttons(
+        &mut self,
+        device_id: DeviceId,
+        buttons: HashMap<ButtonId, Vec<ButtonAction>>,
+    ) {
+        info!("[DeviceRegistry]: set buttons of {:?}", device_id);
+
+        let config = self.entry(device_id);
+        let default_profile = config.default_profile.clone();
+
+        config.profiles.entry(default_profile).or_default().buttons = buttons
+            .into_iter()
+            .map(|(button_id, actions)| (button_id, ButtonMapping::from(actions)))
+            .collect();
+    }
+
+    pub fn set_profile(&mut self, device_id: DeviceId, name: impl Into<String>, profile: Profile) {
+        self.entry(device_id).profiles.insert(name.into(), profile);
+    }
+
+    pub fn set_default_profile(&mut self, device_id: DeviceId, name: impl Into<String>) {
+        self.entry(device_id).default_profile = name.into();
+    }
+
+    pub fn set_gamepad_slot(&mut self, device_id: DeviceId, slot: Option<u8>) {
+        self.entry(device_id).gamepad_slot = slot;
+    }
+
+    pub fn set_axis_calibration(
+        &mut self,
+        device_id: DeviceId,
+        axis: impl Into<AxisKey>,
+        calibration: AxisCalibration,
+    ) {
+        self.entry(device_id).axes.insert(axis.into(), calibration);
+    }
+
+    pub fn axis_calibration(&self, device_id: &DeviceId, axis: &str) -> Option<AxisCalibration> {
+        self.get(device_id)
+            .and_then(|config| config.axes.get(axis))
+            .copied()
+    }
+
+    pub fn set_axis_filter(
+        &mut self,
+        device_id: DeviceId,
+        axis: impl Into<AxisKey>,
+        filter: FilterConfig,
+    ) {
+        self.entry(device_id).filters.insert(axis.into(), filter);
+    }
+
+    pub fn axis_filter(&self, device_id: &DeviceId, axis: &str) -> Option<FilterConfig> {
+        self.get(device_id)
+            .and_then(|config| config.filters.get(axis))
+            .copied()
+    }
+
+    pub fn set_axis_route(
+        &mut self,
+        device_id: DeviceId,
+        axis: impl Into<AxisKey>,
+        route: AxisRoute,
+    ) {
+        self.entry(device_id).routes.insert(axis.into(), route);
+    }
+
+    pub fn axis_route(&self, device_id: &DeviceId, axis: &str) -> AxisRoute {
+        self.get(device_id)
+            .and_then(|config| config.routes.get(axis))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn set_hat_route(&mut self, device_id: DeviceId, hat: impl Into<AxisKey>, route: HatRoute) {
+        self.entry(device_id).hats.insert(hat.into(), route);
+    }
+
+    pub fn hat_route(&self, device_id: &DeviceId, hat: &str) -> Option<HatRoute> {
+        self.get(device_id)
+            .and_then(|config| config.hats.get(hat))
+            .cloned()
+    }
+
+    // The session opens the file with the next message, a .jsonl extension records json, anything else bincode
+    pub fn start_recording(&mut self, device_id: DeviceId, path: impl Into<PathBuf>) {
+        let path = path.into();
+        info!("[DeviceRegistry]: recording {:?} to {:?}", device_id, path);
+
+        self.recordings.insert(device_id, path);
+    }
+
+    pub fn stop_recording(&mut self, device_id: &DeviceId) -> Option<PathBuf> {
+        self.recordings.remove(device_id)
+    }
+
+    pub fn recording(&self, device_id: &DeviceId) -> Option<&Path> {
+        self.recordings.get(device_id).map(PathBuf::as_path)
+    }
+
+    pub fn start_capture(&mut self, device_id: DeviceId) {
+        info!("[DeviceRegistry]: capturing calibration of {:?}", device_id);
+
+        self.captures.insert(device_id, HashMap::new());
+    }
+
+    pub fn is_capturing(&self, device_id: &DeviceId) -> bool {
+        self.captures.contains_key(device_id)
+    }
+
+    pub fn record_capture(&mut self, device_id: &DeviceId, axis: &str, value: f64) {
+        if let Some(capture) = self.captures.get_mut(device_id) {
+            capture
+                .entry(axis.to_string())
+                .and_modify(|capture| capture.record(value))
+                .or_insert_with(|| AxisCapture::new(value));
+        }
+    }
+
+    // Returns what the device reported, with apply the captured range replaces the calibration of each axis
+    pub fn stop_capture(
+        &mut self,
+        device_id: DeviceId,
+        apply: bool,
+    ) -> Option<HashMap<AxisKey, AxisCapture>> {
+        let captures = self.captures.remove(&device_id)?;
+
+        if apply {
+            let axes = &mut self.entry(device_id).axes;
+
+            for (axis, capture) in &captures {
+                capture.apply_to(axes.entry(axis.clone()).or_default());
+            }
+        }
+
+        Some(captures)
+    }
+}
+
+pub type Registry = Arc<RwLock<DeviceRegistry>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jojo_common::keyboard::{Key, KeyboardButton};
+
+    #[test]
+    fn test_resolve_layers() {
+        let device_id = DeviceId::new_v4();
+        let button_id = ButtonId::new_v4();
+        let base = vec![ButtonAction::KeyboardButton(KeyboardButton::Key(
+            Key::Space,
+        ))];
+        let shifted = ButtonMapping::from(vec![ButtonAction::KeyboardButton(
+            KeyboardButton::Sequence("jojo".to_string()),
+        )]);
+
+        let mut registry = DeviceRegistry::new();
+
+        registry.set_buttons(device_id, HashMap::from([(button_id, base.clone())]));
+        registry
+            .entry(device_id)
+            .profiles
+            .get_mut(DEFAULT_PROFILE)
+            .unwrap()
+            .layers
+            .insert(
+                "shift".to_string(),
+                Layer {
+                    buttons: HashMap::from([(button_id, shifted.clone())]),
+                },
+            );
+
+        let config = registry.get(&device_id).unwrap();
+
+        assert_eq!(
+            config.resolve(DEFAULT_PROFILE, &[], &button_id),
+            Some(&ButtonMapping::from(base))
+        );
+        assert_eq!(
+            config.resolve(DEFAULT_PROFILE, &["shift".to_string()], &button_id),
+            Some(&shifted)
+        );
+        assert_eq!(config.resolve("game", &[], &button_id), None);
+        assert_eq!(
+            config.resolve(DEFAULT_PROFILE, &[], &ButtonId::new_v4()),
+            None
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use crate::registry::{ButtonId, MappedAction};
+use jojo_common::button::ButtonAction;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+// Faster than this most games and the virtual drivers drop presses
+pub const MAX_TURBO_HZ: f64 = 30.0;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum RepeatMode {
+    #[default]
+    Once,
+    // Press and release cycles per second while the button is held
+    Turbo {
+        hz: f64,
+    },
+    // Like a keyboard, the first repeat waits longer than the rest
+    AutoRepeat {
+        delay_millis: u64,
+        interval_millis: u64,
+    },
+    // The first press latches the button, the next one releases it
+    ToggleHold,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Release,
+    Press,
+}
+
+impl RepeatMode {
+    // Wait before the step and the actions it runs, the button is pressed when the repeats start and every
+    // release is followed by a press so the held state is the same when they stop
+    pub fn step(&self, index: u64) -> Option<(Duration, Phase)> {
+        let phase = match index % 2 {
+            0 => Phase::Release,
+            _ => Phase::Press,
+        };
+
+        match *self {
+            RepeatMode::Turbo { hz } if hz > 0.0 => {
+                let half_cycle = Duration::from_secs_f64(0.5 / hz.min(MAX_TURBO_HZ));
+
+                Some((half_cycle, phase))
+            }
+            RepeatMode::AutoRepeat {
+                delay_millis,
+                interval_millis,
+            } => {
+                let wait = match (index, phase) {
+                    (0, _) => delay_millis,
+                    (_, Phase::Release) => interval_millis,
+                    (_, Phase::Press) => 0,
+                };
+
+                Some((Duration::from_millis(wait), phase))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn repeats(&self) -> bool {
+        self.step(0).is_some()
+    }
+}
+
+pub fn button_actions(mapped_actions: &[MappedAction]) -> Vec<ButtonAction> {
+    mapped_actions
+        .iter()
+        .filter_map(|mapped_action| match mapped_action {
+            MappedAction::Action(button_action) => Some(button_action.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+// Repeat tasks of the held buttons of a session, they are stopped on release or when the session is dropped
+#[derive(Debug, Default)]
+pub struct Repeats {
+    tasks: HashMap<ButtonId, JoinHandle<()>>,
+}
+
+impl Repeats {
+    pub fn start(&mut self, button_id: ButtonId, task: JoinHandle<()>) {
+        if let Some(previous) = self.tasks.insert(button_id, task) {
+            previous.abort();
+        }
+    }
+
+    pub fn stop(&mut self, button_id: &ButtonId) -> bool {
+        match self.tasks.remove(button_id) {
+            Some(task) => {
+                task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Drop for Repeats {
+    fn drop(&mut self) {
+        self.tasks.drain().for_each(|(_, task)| task.abort());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_turbo() {
+        let turbo = RepeatMode::Turbo { hz: 10.0 };
+        let half_cycle = Duration::from_millis(50);
+
+        assert_eq!(turbo.step(0), Some((half_cycle, Phase::Release)));
+        assert_eq!(turbo.step(1), Some((half_cycle, Phase::Press)));
+        assert_eq!(turbo.step(2), Some((half_cycle, Phase::Release)));
+
+        let capped = RepeatMode::Turbo { hz: 1_000.0 }.step(0).unwrap().0;
+
+        assert!(capped >= Duration::from_secs_f64(0.5 / MAX_TURBO_HZ));
+        assert!(!RepeatMode::Turbo { hz: 0.0 }.repeats());
+    }
+
+    #[test]
+    fn test_auto_repeat() {
+        let auto_repeat = RepeatMode::AutoRepeat {
+            delay_millis: 500,
+            interval_millis: 30,
+        };
+
+        assert_eq!(
+            auto_repeat.step(0),
+            Some((Duration::from_millis(500), Phase::Release))
+        );
+        assert_eq!(auto_repeat.step(1), Some((Duration::ZERO, Phase::Press)));
+        assert_eq!(
+            auto_repeat.step(2),
+            Some((Duration::from_millis(30), Phase::Release))
+        );
+        assert!(!RepeatMode::Once.repeats());
+        assert!(!RepeatMode::ToggleHold.repeats());
+    }
+}
@@ -0,0 +1,429 @@
+use crate::calibration::{AxisKey, AXIS_MAX, AXIS_MIN};
+use jojo_common::button::ButtonAction;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+// Pointer and scroll routes move the mouse from a ticker, axis reads only arrive when the value changes
+pub const POINTER_TICK_MILLIS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PointerAxis {
+    Horizontal,
+    Vertical,
+}
+
+// Actions run when a zone or hat direction is entered, and when it's left. Holding a key needs a press
+// and a release action, e.g. the `{+W}` and `{-W}` sequences of the dsl
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ZoneActions {
+    pub press: Vec<ButtonAction>,
+    #[serde(default)]
+    pub release: Vec<ButtonAction>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum AxisRoute {
+    #[default]
+    Gamepad,
+    // Speed in pixels per second at full deflection
+    Pointer {
+        direction: PointerAxis,
+        speed: f64,
+    },
+    // Speed in wheel notches per second at full deflection, positive scrolls down or right
+    Scroll {
+        direction: PointerAxis,
+        speed: f64,
+    },
+    // A pair of digital zones, the threshold is a fraction of the deflection from the center
+    Keys {
+        negative: ZoneActions,
+        positive: ZoneActions,
+        threshold: f64,
+    },
+}
+
+// Hat directions are keyed by their json name ("Up", "DownLeft", ...), a missing direction does nothing
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HatRoute {
+    pub directions: HashMap<String, ZoneActions>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Zone {
+    Negative,
+    #[default]
+    Center,
+    Positive,
+}
+
+impl Zone {
+    pub fn new(deflection: f64, threshold: f64) -> Self {
+        let threshold = threshold.clamp(0.0, 1.0);
+
+        match deflection {
+            deflection if deflection <= -threshold && deflection < 0.0 => Zone::Negative,
+            deflection if deflection >= threshold && deflection > 0.0 => Zone::Positive,
+            _ => Zone::Center,
+        }
+    }
+}
+
+// Calibrated axis value to -1..1 around the center of the gamepad range
+pub fn deflection(value: f64) -> f64 {
+    let center = (AXIS_MIN + AXIS_MAX) / 2.0;
+
+    ((value - center) / (AXIS_MAX - center)).clamp(-1.0, 1.0)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PointerVelocity {
+    pub pointer: (f64, f64),
+    pub scroll: (f64, f64),
+    // Fractions of a pixel or notch carried to the next tick, so slow speeds still move
+    remainder: [f64; 4],
+}
+
+impl PointerVelocity {
+    pub fn set(&mut self, scroll: bool, direction: PointerAxis, speed: f64) {
+        let velocity = match scroll {
+            true => &mut self.scroll,
+            false => &mut self.pointer,
+        };
+
+        match direction {
+            PointerAxis::Horizontal => velocity.0 = speed,
+            PointerAxis::Vertical => velocity.1 = speed,
+        }
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.pointer == (0.0, 0.0) && self.scroll == (0.0, 0.0)
+    }
+
+    // Whole pixels and notches to move for the elapsed time, as (x, y, scroll x, scroll y)
+    pub fn step(&mut self, elapsed: Duration) -> (i32, i32, i32, i32) {
+        let speeds = [self.pointer.0, self.pointer.1, self.scroll.0, self.scroll.1];
+        let mut steps = [0; 4];
+
+        for (index, speed) in speeds.into_iter().enumerate() {
+            match speed == 0.0 {
+                true => self.remainder[index] = 0.0,
+                false => {
+                    let total = self.remainder[index] + speed * elapsed.as_secs_f64();
+                    let whole = total.trunc();
+
+                    self.remainder[index] = total - whole;
+                    steps[index] = whole as i32;
+                }
+            }
+        }
+
+        (steps[0], steps[1], steps[2], steps[3])
+    }
+}
+
+// Routing state of a connected device, the ticker is stopped when the session is dropped
+#[derive(Debug, Default)]
+pub struct RouteState {
+    zones: HashMap<AxisKey, Zone>,
+    hats: HashMap<AxisKey, String>,
+    pub velocity: Arc<Mutex<PointerVelocity>>,
+    pub ticker: Option<JoinHandle<()>>,
+}
+
+impl RouteState {
+    // Release actions of the zone left followed by the press actions of the zone entered
+    pub fn enter_zone(
+        &mut self,
+        axis: &str,
+        zone: Zone,
+        negative: &ZoneActions,
+        positive: &ZoneActions,
+    ) -> Vec<ButtonAction> {
+        let previous = self
+            .zones
+            .insert(axis.to_string(), zone)
+            .unwrap_or_default();
+
+        if previous == zone {
+            return Vec::new();
+        }
+
+        let actions = |zone: Zone| match zone {
+            Zone::Negative => Some(negative),
+            Zone::Positive => Some(positive),
+            Zone::Center => None,
+        };
+
+        let mut button_actions = Vec::new();
+
+        if let Some(left) = actions(previous) {
+            button_actions.extend(left.release.iter().cloned());
+        }
+
+        if let Some(entered) = actions(zone) {
+            button_actions.extend(entered.press.iter().cloned());
+        }
+
+        button_actions
+    }
+
+    // Release actions of every zone the session left entered, so no key stays down once it's gone
+    pub fn release_zones(&mut self, routes: &HashMap<AxisKey, AxisRoute>) -> Vec<ButtonAction> {
+        let mut button_actions = Vec::new();
+
+        for (axis, zone) in self.zones.drain() {
+            let Some(AxisRoute::Keys {
+                negative, positive, ..
+            }) = routes.get(&axis)
+            else {
+                continue;
+            };
+
+            match zone {
+                Zone::Negative => button_actions.extend(negative.release.iter().cloned()),
+                Zone::Positive => button_actions.extend(positive.release.iter().cloned()),
+                Zone::Center => {}
+            }
+        }
+
+        button_actions
+    }
+
+    pub fn enter_direction(
+        &mut self,
+        hat: &str,
+        direction: String,
+        route: &HatRoute,
+    ) -> Vec<ButtonAction> {
+        let previous = self.hats.insert(hat.to_string(), direction.clone());
+
+        if previous.as_ref() == Some(&direction) {
+            return Vec::new();
+        }
+
+        let mut button_actions = Vec::new();
+
+        if let Some(left) = previous.and_then(|previous| route.directions.get(&previous)) {
+            button_actions.extend(left.release.iter().cloned());
+        }
+
+        if let Some(entered) = route.directions.get(&direction) {
+            button_actions.extend(entered.press.iter().cloned());
+        }
+
+        button_actions
+    }
+
+    // Release actions of the hat directions the session left entered
+    pub fn release_directions(&mut self, hats: &HashMap<AxisKey, HatRoute>) -> Vec<ButtonAction> {
+        self.hats
+            .drain()
+            .filter_map(|(hat, direction)| hats.get(&hat)?.directions.get(&direction).cloned())
+            .flat_map(|actions| actions.release)
+            .collect()
+    }
+}
+
+impl Drop for RouteState {
+    fn drop(&mut self) {
+        if let Some(ticker) = self.ticker.take() {
+            ticker.abort();
+        }
+    }
+}
+
+// MouseDriver has no wheel, scrolling goes straight to the os
+#[cfg(windows)]
+pub fn scroll(x: i32, y: i32) {
+    use windows_sys::Win32::UI::Input::KeyboardAndMouse::{
+        SendInput, INPUT, INPUT_0, INPUT_MOUSE, MOUSEEVENTF_HWHEEL, MOUSEEVENTF_WHEEL, MOUSEINPUT,
+    };
+    use windows_sys::Win32::UI::WindowsAndMessaging::WHEEL_DELTA;
+
+    let wheel = |notches: i32, flags| INPUT {
+        r#type: INPUT_MOUSE,
+        Anonymous: INPUT_0 {
+            mi: MOUSEINPUT {
+                dx: 0,
+                dy: 0,
+                mouseData: notches.saturating_mul(WHEEL_DELTA as i32),
+                dwFlags: flags,
+                time: 0,
+                dwExtraInfo: 0,
+            },
+        },
+    };
+
+    // A positive wheel scrolls up, the route scrolls down like the pointer moves down
+    let inputs: Vec<INPUT> = [
+        (y.saturating_neg(), MOUSEEVENTF_WHEEL),
+        (x, MOUSEEVENTF_HWHEEL),
+    ]
+    .into_iter()
+    .filter(|(notches, _)| *notches != 0)
+    .map(|(notches, flags)| wheel(notches, flags))
+    .collect();
+
+    if inputs.is_empty() {
+        return;
+    }
+
+    let sent = unsafe {
+        SendInput(
+            inputs.len() as u32,
+            inputs.as_ptr(),
+            std::mem::size_of::<INPUT>() as i32,
+        )
+    };
+
+    if sent != inputs.len() as u32 {
+        warn!("[scroll]: input was blocked by another application");
+    }
+}
+
+#[cfg(not(windows))]
+pub fn scroll(x: i32, y: i32) {
+    static WARNED: std::sync::Once = std::sync::Once::new();
+
+    WARNED.call_once(|| {
+        warn!(
+            "[scroll]: not supported on this platform, {} {} dropped",
+            x, y
+        )
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jojo_common::keyboard::KeyboardButton;
+
+    fn sequence(sequence: &str) -> ButtonAction {
+        ButtonAction::KeyboardButton(KeyboardButton::SequenceDsl(sequence.to_string()))
+    }
+
+    #[test]
+    fn test_zones() {
+        let negative = ZoneActions {
+            press: vec![sequence("{+A}")],
+            release: vec![sequence("{-A}")],
+        };
+        let positive = ZoneActions {
+            press: vec![sequence("{+D}")],
+            release: vec![sequence("{-D}")],
+        };
+        let mut state = RouteState::default();
+
+        assert_eq!(Zone::new(deflection(AXIS_MIN), 0.5), Zone::Negative);
+        assert_eq!(Zone::new(deflection(AXIS_MAX * 0.6), 0.5), Zone::Center);
+        assert_eq!(Zone::new(0.0, 0.0), Zone::Center);
+
+        assert_eq!(
+            state.enter_zone("X", Zone::Negative, &negative, &positive),
+            vec![sequence("{+A}")]
+        );
+        assert!(state
+            .enter_zone("X", Zone::Negative, &negative, &positive)
+            .is_empty());
+        assert_eq!(
+            state.enter_zone("X", Zone::Positive, &negative, &positive),
+            vec![sequence("{-A}"), sequence("{+D}")]
+        );
+        assert_eq!(
+            state.enter_zone("X", Zone::Center, &negative, &positive),
+            vec![sequence("{-D}")]
+        );
+    }
+
+    #[test]
+    fn test_release_zones() {
+        let negative = ZoneActions {
+            press: vec![sequence("{+A}")],
+            release: vec![sequence("{-A}")],
+        };
+        let positive = ZoneActions {
+            press: vec![sequence("{+D}")],
+            release: vec![sequence("{-D}")],
+        };
+        let routes = HashMap::from([(
+            "X".to_string(),
+            AxisRoute::Keys {
+                negative: negative.clone(),
+                positive: positive.clone(),
+                threshold: 0.5,
+            },
+        )]);
+        let mut state = RouteState::default();
+
+        state.enter_zone("X", Zone::Positive, &negative, &positive);
+        state.enter_zone("Y", Zone::Negative, &negative, &positive);
+
+        // Y isn't routed to keys anymore, only X has something to release
+        assert_eq!(state.release_zones(&routes), vec![sequence("{-D}")]);
+        assert!(state.release_zones(&routes).is_empty());
+    }
+
+    #[test]
+    fn test_hat_directions() {
+        let route = HatRoute {
+            directions: HashMap::from([(
+                "Up".to_string(),
+                ZoneActions {
+                    press: vec![sequence("{+UP}")],
+                    release: vec![sequence("{-UP}")],
+                },
+            )]),
+        };
+        let mut state = RouteState::default();
+
+        assert_eq!(
+            state.enter_direction("Hat1", "Up".to_string(), &route),
+            vec![sequence("{+UP}")]
+        );
+        assert!(state
+            .enter_direction("Hat1", "Up".to_string(), &route)
+            .is_empty());
+        assert_eq!(
+            state.enter_direction("Hat1", "Centered".to_string(), &route),
+            vec![sequence("{-UP}")]
+        );
+
+        state.enter_direction("Hat1", "Up".to_string(), &route);
+        let hats = HashMap::from([("Hat1".to_string(), route)]);
+
+        assert_eq!(state.release_directions(&hats), vec![sequence("{-UP}")]);
+        assert!(state.release_directions(&hats).is_empty());
+    }
+
+    #[test]
+    fn test_pointer_step() {
+        let mut velocity = PointerVelocity::default();
+        let tick = Duration::from_millis(POINTER_TICK_MILLIS);
+
+        velocity.set(false, PointerAxis::Horizontal, 50.0);
+        velocity.set(false, PointerAxis::Vertical, -200.0);
+        velocity.set(true, PointerAxis::Vertical, 25.0);
+
+        // Half a pixel per tick moves one pixel every second tick, a quarter notch scrolls every fourth
+        assert_eq!(velocity.step(tick), (0, -2, 0, 0));
+        assert_eq!(velocity.step(tick), (1, -2, 0, 0));
+        assert_eq!(velocity.step(tick), (0, -2, 0, 0));
+        assert_eq!(velocity.step(tick), (1, -2, 0, 1));
+
+        velocity.set(false, PointerAxis::Horizontal, 0.0);
+        velocity.set(false, PointerAxis::Vertical, 0.0);
+
+        assert!(!velocity.is_idle());
+
+        velocity.set(true, PointerAxis::Vertical, 0.0);
+
+        assert!(velocity.is_idle());
+        assert_eq!(velocity.step(tick), (0, 0, 0, 0));
+    }
+}
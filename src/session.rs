@@ -0,0 +1,400 @@
+use crate::calibration::AxisKey;
+use crate::event::SessionInfo;
+use crate::filter::AxisFilter;
+use crate::gamepad::SharedGamepad;
+use crate::protocol::{Encoding, Peer};
+use crate::recorder::Recorder;
+use crate::registry::{ButtonId, ButtonMapping, DeviceConfig, MappedAction};
+use crate::repeat::{RepeatMode, Repeats};
+use crate::routing::RouteState;
+use jojo_common::button::ButtonAction;
+use jojo_common::device::DeviceId;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ActiveProfile {
+    pub profile: String,
+    pub layers: Vec<String>,
+}
+
+// Profile and layers selected by a connected device, they go back to the defaults on reconnect
+#[derive(Debug, Clone, Default)]
+pub struct MappingState {
+    profile: Option<String>,
+    layers: Vec<String>,
+    // Mapping resolved when each button was pressed, the release must use it even if the layers changed since
+    held: HashMap<ButtonId, ButtonMapping>,
+    // Toggle hold buttons waiting for the press that releases them
+    latched: HashMap<ButtonId, ButtonMapping>,
+}
+
+impl MappingState {
+    pub fn active(&self, config: &DeviceConfig) -> ActiveProfile {
+        ActiveProfile {
+            profile: self
+                .profile
+                .clone()
+                .unwrap_or_else(|| config.default_profile.clone()),
+            layers: self.layers.clone(),
+        }
+    }
+
+    // Both press and release return the actions for the drivers, plus if the active profile or layers changed
+    pub fn press(
+        &mut self,
+        config: &DeviceConfig,
+        button_id: ButtonId,
+    ) -> (Vec<ButtonAction>, bool) {
+        let before = self.active(config);
+
+        if let Some(mapping) = self.latched.remove(&button_id) {
+            let button_actions = self.release_mapping(config, mapping);
+
+            return (button_actions, self.active(config) != before);
+        }
+
+        let actions = match config.resolve(&before.profile, &before.layers, &button_id) {
+            Some(mapping) => {
+                if mapping.repeat == RepeatMode::ToggleHold {
+                    self.latched.insert(button_id, mapping.clone());
+                }
+
+                self.held.insert(button_id, mapping.clone());
+                mapping.press.clone()
+            }
+            None => Vec::new(),
+        };
+
+        let button_actions = self.apply(config, actions);
+
+        (button_actions, self.active(config) != before)
+    }
+
+    pub fn release(
+        &mut self,
+        config: &DeviceConfig,
+        button_id: ButtonId,
+    ) -> (Vec<ButtonAction>, bool) {
+        let Some(mapping) = self.held.remove(&button_id) else {
+            return (Vec::new(), false);
+        };
+
+        if self.latched.contains_key(&button_id) {
+            return (Vec::new(), false);
+        }
+
+        let before = self.active(config);
+        let button_actions = self.release_mapping(config, mapping);
+
+        (button_actions, self.active(config) != before)
+    }
+
+    // Releases every button still pressed or latched by toggle hold, for a session that ends before their release
+    pub fn release_all(&mut self, config: &DeviceConfig) -> Vec<ButtonAction> {
+        let mut mappings: HashMap<ButtonId, ButtonMapping> = self.held.drain().collect();
+        mappings.extend(self.latched.drain());
+
+        mappings
+            .into_values()
+            .flat_map(|mapping| self.release_mapping(config, mapping))
+            .collect()
+    }
+
+    // Mapping of a button that is physically pressed right now
+    pub fn held(&self, button_id: &ButtonId) -> Option<&ButtonMapping> {
+        self.held.get(button_id)
+    }
+
+    fn release_mapping(
+        &mut self,
+        config: &DeviceConfig,
+        mapping: ButtonMapping,
+    ) -> Vec<ButtonAction> {
+        // Momentary layers pushed by this button go away with the release
+        for action in &mapping.press {
+            if let MappedAction::MomentaryLayer(layer) = action {
+                self.remove_layer(layer);
+            }
+        }
+
+        self.apply(config, mapping.release)
+    }
+
+    fn apply(&mut self, config: &DeviceConfig, actions: Vec<MappedAction>) -> Vec<ButtonAction> {
+        let mut button_actions = Vec::new();
+
+        for action in actions {
+            match action {
+                MappedAction::Action(button_action) => button_actions.push(button_action),
+                MappedAction::SwitchProfile(profile) if config.profiles.contains_key(&profile) => {
+                    self.profile = Some(profile);
+                    self.layers.clear();
+                }
+                MappedAction::SwitchProfile(profile) => {
+                    warn!("[mapping_state]: profile {} doesn't exist", profile)
+                }
+                MappedAction::MomentaryLayer(layer) | MappedAction::PushLayer(layer) => {
+                    self.layers.push(layer)
+                }
+                MappedAction::ToggleLayer(layer) => {
+                    if !self.remove_layer(&layer) {
+                        self.layers.push(layer);
+                    }
+                }
+                MappedAction::PopLayer => {
+                    self.layers.pop();
+                }
+            }
+        }
+
+        button_actions
+    }
+
+    fn remove_layer(&mut self, layer: &str) -> bool {
+        match self.layers.iter().rposition(|active| active == layer) {
+            Some(index) => {
+                self.layers.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// A session that couldn't get a gamepad waits this long before asking the pool again
+pub const GAMEPAD_RETRY: Duration = Duration::from_secs(5);
+
+// Button actions with the gamepad they need
+pub type ActionBatch = (Option<SharedGamepad>, Vec<ButtonAction>);
+
+// State owned by a single connection, dropped when the socket closes
+pub struct Session {
+    pub device_id: DeviceId,
+    pub mapping: MappingState,
+    // Acquired with the first gamepad message, the slot goes back to the pool on disconnect
+    pub gamepad: Option<(u8, SharedGamepad)>,
+    // Last failed acquire, axis reads stream too fast to ask the pool for each of them
+    pub gamepad_failed_at: Option<Instant>,
+    pub filters: HashMap<AxisKey, AxisFilter>,
+    pub routes: RouteState,
+    pub repeats: Repeats,
+    pub recorder: Option<Recorder>,
+    pub info: SessionInfo,
+    // Pings carry the time since then
+    pub connected_at: Instant,
+    // Negotiated with the hello, the socket writer follows it through a receiver
+    pub peer: watch::Sender<Peer>,
+    // Picked at the upgrade, a hello can't change it anymore
+    pub subprotocol: Option<Encoding>,
+    // Set by the first message, a hello has to come before anything else
+    pub started: bool,
+    // Worker running the button actions in order, without one they run inline like in replays
+    pub actions_tx: Option<mpsc::Sender<ActionBatch>>,
+    // Fed by a replay, it isn't recorded again
+    pub replaying: bool,
+}
+
+impl Session {
+    pub fn new(device_id: DeviceId) -> Self {
+        Session {
+            device_id,
+            mapping: MappingState::default(),
+            gamepad: None,
+            gamepad_failed_at: None,
+            filters: HashMap::new(),
+            routes: RouteState::default(),
+            repeats: Repeats::default(),
+            recorder: None,
+            info: SessionInfo::new(None),
+            connected_at: Instant::now(),
+            peer: watch::channel(Peer::default()).0,
+            subprotocol: None,
+            started: false,
+            actions_tx: None,
+            replaying: false,
+        }
+    }
+
+    pub fn with_info(mut self, info: SessionInfo) -> Self {
+        self.info = info;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::{Layer, Profile, DEFAULT_PROFILE};
+    use jojo_common::keyboard::KeyboardButton;
+
+    fn config(shift_id: ButtonId, toggle_id: ButtonId, button_id: ButtonId) -> DeviceConfig {
+        let mut config = DeviceConfig::default();
+
+        config.profiles.insert(
+            DEFAULT_PROFILE.to_string(),
+            Profile {
+                buttons: HashMap::from([
+                    (
+                        shift_id,
+                        ButtonMapping {
+                            press: vec![MappedAction::MomentaryLayer("shift".to_string())],
+                            ..Default::default()
+                        },
+                    ),
+                    (
+                        toggle_id,
+                        ButtonMapping {
+                            press: vec![MappedAction::SwitchProfile("game".to_string())],
+                            ..Default::default()
+                        },
+                    ),
+                ]),
+                layers: HashMap::from([(
+                    "shift".to_string(),
+                    Layer {
+                        buttons: HashMap::from([(
+                            button_id,
+                            ButtonMapping {
+                                press: vec![MappedAction::PopLayer],
+                                ..Default::default()
+                            },
+                        )]),
+                    },
+                )]),
+            },
+        );
+        config
+            .profiles
+            .insert("game".to_string(), Profile::default());
+
+        config
+    }
+
+    #[test]
+    fn test_momentary_layer() {
+        let (shift_id, toggle_id, button_id) =
+            (ButtonId::new_v4(), ButtonId::new_v4(), ButtonId::new_v4());
+        let config = config(shift_id, toggle_id, button_id);
+        let mut state = MappingState::default();
+
+        let (_, pressed) = state.press(&config, shift_id);
+
+        assert!(pressed);
+        assert_eq!(state.active(&config).layers, vec!["shift".to_string()]);
+
+        let (_, released) = state.release(&config, shift_id);
+        let (_, unmapped) = state.press(&config, button_id);
+
+        assert!(released);
+        assert!(!unmapped);
+        assert!(state.active(&config).layers.is_empty());
+    }
+
+    #[test]
+    fn test_layer_button() {
+        let (shift_id, toggle_id, button_id) =
+            (ButtonId::new_v4(), ButtonId::new_v4(), ButtonId::new_v4());
+        let config = config(shift_id, toggle_id, button_id);
+        let mut state = MappingState::default();
+
+        state.press(&config, shift_id);
+
+        // The shift layer maps button_id to a PopLayer
+        let (_, popped) = state.press(&config, button_id);
+        let (_, released) = state.release(&config, shift_id);
+
+        assert!(popped);
+        assert!(!released);
+        assert!(state.active(&config).layers.is_empty());
+    }
+
+    #[test]
+    fn test_switch_profile() {
+        let (shift_id, toggle_id, button_id) =
+            (ButtonId::new_v4(), ButtonId::new_v4(), ButtonId::new_v4());
+        let config = config(shift_id, toggle_id, button_id);
+        let mut state = MappingState::default();
+
+        assert_eq!(state.active(&config).profile, DEFAULT_PROFILE);
+
+        let (button_actions, changed) = state.press(&config, toggle_id);
+
+        assert!(changed);
+        assert!(button_actions.is_empty());
+        assert_eq!(state.active(&config).profile, "game");
+        assert!(!state.press(&config, shift_id).1);
+    }
+
+    #[test]
+    fn test_toggle_hold() {
+        let button_id = ButtonId::new_v4();
+        let mut config = DeviceConfig::default();
+        let press = ButtonAction::KeyboardButton(KeyboardButton::SequenceDsl("{+W}".to_string()));
+        let release = ButtonAction::KeyboardButton(KeyboardButton::SequenceDsl("{-W}".to_string()));
+
+        config
+            .profiles
+            .get_mut(DEFAULT_PROFILE)
+            .unwrap()
+            .buttons
+            .insert(
+                button_id,
+                ButtonMapping {
+                    press: vec![MappedAction::Action(press.clone())],
+                    release: vec![MappedAction::Action(release.clone())],
+                    repeat: RepeatMode::ToggleHold,
+                },
+            );
+
+        let mut state = MappingState::default();
+
+        assert_eq!(state.press(&config, button_id).0, vec![press]);
+        assert!(state.release(&config, button_id).0.is_empty());
+        assert_eq!(state.press(&config, button_id).0, vec![release]);
+        assert!(state.release(&config, button_id).0.is_empty());
+    }
+
+    #[test]
+    fn test_release_all() {
+        let (held_id, latched_id) = (ButtonId::new_v4(), ButtonId::new_v4());
+        let mut config = DeviceConfig::default();
+        let sequence = |sequence: &str| {
+            ButtonAction::KeyboardButton(KeyboardButton::SequenceDsl(sequence.to_string()))
+        };
+        let buttons = &mut config.profiles.get_mut(DEFAULT_PROFILE).unwrap().buttons;
+
+        buttons.insert(
+            held_id,
+            ButtonMapping {
+                press: vec![MappedAction::Action(sequence("{+A}"))],
+                release: vec![MappedAction::Action(sequence("{-A}"))],
+                ..Default::default()
+            },
+        );
+        buttons.insert(
+            latched_id,
+            ButtonMapping {
+                press: vec![MappedAction::Action(sequence("{+W}"))],
+                release: vec![MappedAction::Action(sequence("{-W}"))],
+                repeat: RepeatMode::ToggleHold,
+            },
+        );
+
+        let mut state = MappingState::default();
+
+        state.press(&config, held_id);
+        state.press(&config, latched_id);
+        state.release(&config, latched_id);
+
+        let mut button_actions = state.release_all(&config);
+        button_actions.sort_by_key(|button_action| format!("{:?}", button_action));
+
+        assert_eq!(button_actions, vec![sequence("{-A}"), sequence("{-W}")]);
+        assert!(state.release_all(&config).is_empty());
+        assert!(state.release(&config, held_id).0.is_empty());
+    }
+}
@@ -0,0 +1,256 @@
+use jojo_common::device::DeviceId;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Samples kept per device, devices report every few seconds so this is a few minutes
+pub const HISTORY_LEN: usize = 120;
+pub const LOW_BATTERY_PERCENT: u8 = 15;
+pub const WEAK_RSSI_DBM: i16 = -80;
+// Longer versions are cut, every sample keeps its own copy
+pub const MAX_FIRMWARE_VERSION_LEN: usize = 64;
+// History of a disconnected device is kept this long after its last sample
+pub const HISTORY_TTL: Duration = Duration::from_secs(60 * 60);
+
+// Sent by jojo-client as ExtClientMessage::Telemetry
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Telemetry {
+    pub battery_percent: u8,
+    pub charging: bool,
+    pub rssi_dbm: i16,
+    pub free_heap_bytes: u32,
+    pub firmware_version: String,
+    pub uptime_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TelemetrySample {
+    // Wall clock, the app shows it next to the sample
+    pub received_unix_millis: u64,
+    pub telemetry: Telemetry,
+}
+
+// Raised when a threshold is crossed, not on every sample past it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TelemetryAlert {
+    LowBattery(u8),
+    WeakSignal(i16),
+    // The device was flashed since its last sample
+    FirmwareChanged(String),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TelemetryHistory {
+    samples: VecDeque<TelemetrySample>,
+}
+
+impl TelemetryHistory {
+    pub fn push(&mut self, mut telemetry: Telemetry) -> Vec<TelemetryAlert> {
+        truncate(&mut telemetry.firmware_version, MAX_FIRMWARE_VERSION_LEN);
+
+        let alerts = alerts(self.latest().map(|sample| &sample.telemetry), &telemetry);
+
+        if self.samples.len() == HISTORY_LEN {
+            self.samples.pop_front();
+        }
+
+        self.samples.push_back(TelemetrySample {
+            received_unix_millis: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_millis() as u64)
+                .unwrap_or_default(),
+            telemetry,
+        });
+
+        alerts
+    }
+
+    pub fn latest(&self) -> Option<&TelemetrySample> {
+        self.samples.back()
+    }
+
+    pub fn samples(&self) -> Vec<TelemetrySample> {
+        self.samples.iter().cloned().collect()
+    }
+}
+
+// Cut on a char boundary, at most len bytes are left
+fn truncate(text: &mut String, len: usize) {
+    if let Some(end) = (0..=len).rev().find(|end| text.is_char_boundary(*end)) {
+        text.truncate(end);
+    }
+}
+
+fn alerts(previous: Option<&Telemetry>, telemetry: &Telemetry) -> Vec<TelemetryAlert> {
+    let low_battery = |telemetry: &Telemetry| {
+        !telemetry.charging && telemetry.battery_percent <= LOW_BATTERY_PERCENT
+    };
+    let weak_signal = |telemetry: &Telemetry| telemetry.rssi_dbm <= WEAK_RSSI_DBM;
+
+    let mut alerts = Vec::new();
+
+    if low_battery(telemetry) && !previous.is_some_and(low_battery) {
+        alerts.push(TelemetryAlert::LowBattery(telemetry.battery_percent));
+    }
+
+    if weak_signal(telemetry) && !previous.is_some_and(weak_signal) {
+        alerts.push(TelemetryAlert::WeakSignal(telemetry.rssi_dbm));
+    }
+
+    if previous.is_some_and(|previous| previous.firmware_version != telemetry.firmware_version) {
+        alerts.push(TelemetryAlert::FirmwareChanged(
+            telemetry.firmware_version.clone(),
+        ));
+    }
+
+    alerts
+}
+
+// History of every device that reported, it outlives the sessions so a reconnect doesn't lose it
+#[derive(Debug, Clone, Default)]
+pub struct TelemetryStore {
+    // With the time of the last sample
+    devices: Arc<Mutex<HashMap<DeviceId, (TelemetryHistory, Instant)>>>,
+}
+
+impl TelemetryStore {
+    pub fn record(&self, device_id: DeviceId, telemetry: Telemetry) -> Vec<TelemetryAlert> {
+        let mut devices = self.devices.lock().unwrap();
+        let (history, received_at) = devices
+            .entry(device_id)
+            .or_insert_with(|| (TelemetryHistory::default(), Instant::now()));
+
+        *received_at = Instant::now();
+        history.push(telemetry)
+    }
+
+    pub fn latest(&self, device_id: &DeviceId) -> Option<TelemetrySample> {
+        self.devices
+            .lock()
+            .unwrap()
+            .get(device_id)
+            .and_then(|(history, _)| history.latest().cloned())
+    }
+
+    pub fn history(&self, device_id: &DeviceId) -> Vec<TelemetrySample> {
+        self.devices
+            .lock()
+            .unwrap()
+            .get(device_id)
+            .map(|(history, _)| history.samples())
+            .unwrap_or_default()
+    }
+
+    // Same as the metrics, the history of a device that left goes once it is old enough
+    pub fn prune(&self, connected: &[DeviceId], now: Instant) {
+        self.devices
+            .lock()
+            .unwrap()
+            .retain(|device_id, (_, received_at)| {
+                connected.contains(device_id)
+                    || now.saturating_duration_since(*received_at) < HISTORY_TTL
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn telemetry(battery_percent: u8, rssi_dbm: i16) -> Telemetry {
+        Telemetry {
+            battery_percent,
+            charging: false,
+            rssi_dbm,
+            free_heap_bytes: 100_000,
+            firmware_version: "0.1.0".to_string(),
+            uptime_secs: 60,
+        }
+    }
+
+    #[test]
+    fn test_alerts() {
+        let mut history = TelemetryHistory::default();
+
+        assert!(history.push(telemetry(80, -50)).is_empty());
+        assert_eq!(
+            history.push(telemetry(10, -50)),
+            vec![TelemetryAlert::LowBattery(10)]
+        );
+        // Still low, already reported
+        assert!(history.push(telemetry(9, -50)).is_empty());
+        assert_eq!(
+            history.push(telemetry(9, -90)),
+            vec![TelemetryAlert::WeakSignal(-90)]
+        );
+
+        let mut charging = telemetry(9, -50);
+        charging.charging = true;
+        charging.firmware_version = "0.2.0".to_string();
+
+        assert_eq!(
+            history.push(charging),
+            vec![TelemetryAlert::FirmwareChanged("0.2.0".to_string())]
+        );
+        assert_eq!(history.latest().unwrap().telemetry.battery_percent, 9);
+    }
+
+    #[test]
+    fn test_history_len() {
+        let store = TelemetryStore::default();
+        let device_id = DeviceId::new_v4();
+
+        for uptime_secs in 0..HISTORY_LEN as u64 + 10 {
+            store.record(
+                device_id,
+                Telemetry {
+                    uptime_secs,
+                    ..telemetry(80, -50)
+                },
+            );
+        }
+
+        let history = store.history(&device_id);
+
+        assert_eq!(history.len(), HISTORY_LEN);
+        assert_eq!(history[0].telemetry.uptime_secs, 10);
+        assert!(store.history(&DeviceId::new_v4()).is_empty());
+    }
+
+    #[test]
+    fn test_prune() {
+        let (connected, gone) = (DeviceId::new_v4(), DeviceId::new_v4());
+        let store = TelemetryStore::default();
+        let now = Instant::now();
+
+        for device_id in [connected, gone] {
+            store.record(device_id, telemetry(80, -50));
+        }
+
+        store.prune(&[connected], now);
+
+        assert!(store.latest(&gone).is_some());
+
+        store.prune(&[connected], now + HISTORY_TTL);
+
+        assert!(store.latest(&connected).is_some());
+        assert!(store.latest(&gone).is_none());
+    }
+
+    #[test]
+    fn test_firmware_version_len() {
+        let mut history = TelemetryHistory::default();
+
+        history.push(Telemetry {
+            firmware_version: format!("v{}", "é".repeat(MAX_FIRMWARE_VERSION_LEN)),
+            ..telemetry(80, -50)
+        });
+
+        let version = &history.latest().unwrap().telemetry.firmware_version;
+
+        // The last char doesn't fit whole, it goes entirely
+        assert_eq!(version.len(), MAX_FIRMWARE_VERSION_LEN - 1);
+        assert!(version.starts_with("vé"));
+    }
+}
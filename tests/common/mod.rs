@@ -0,0 +1,330 @@
+use futures_util::{SinkExt, StreamExt};
+use jojo_common::device::{Device, DeviceId};
+use jojo_common::message::{ClientMessage, ServerMessage};
+use jojo_common::room::RoomEvent;
+use jojo_server::command::{
+    CommandBackend, CommandReport, CommandRunner, CommandSpec, CommandStatus,
+};
+use jojo_server::config::ServerConfig;
+use jojo_server::control::{ControlCommand, ControlReply};
+use jojo_server::event::ServerEvent;
+use jojo_server::policy::{CommandGuard, CommandPolicy, UnknownCommand};
+use jojo_server::protocol::{self, Encoding, ExtClientMessage, ExtServerMessage, Outbound};
+use jojo_server::registry::Registry;
+use jojo_server::AppState;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+pub const WAIT_MILLIS: u64 = 2_000;
+pub const CONTROL_TOKEN: &str = "test-token";
+
+// Backend that remembers the commands instead of running them
+#[derive(Default)]
+pub struct RecordingBackend {
+    pub runs: Mutex<Vec<CommandSpec>>,
+}
+
+impl CommandBackend for RecordingBackend {
+    fn run(&self, spec: &CommandSpec) -> CommandReport {
+        self.runs.lock().unwrap().push(spec.clone());
+
+        CommandReport::new(spec, CommandStatus::Exited(Some(0)))
+    }
+
+    fn is_running(&self, _pid: u32) -> bool {
+        false
+    }
+
+    fn kill(&self, _pid: u32) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn resolve(&self, spec: &CommandSpec) -> std::io::Result<CommandSpec> {
+        Ok(spec.clone())
+    }
+}
+
+// A server on a free port of the loopback, stopped when dropped
+pub struct TestServer {
+    pub addr: SocketAddr,
+    pub room_rx: mpsc::Receiver<RoomEvent>,
+    pub event_rx: mpsc::Receiver<ServerEvent>,
+    pub tauri_client_tx: broadcast::Sender<ServerMessage>,
+    pub registry: Registry,
+    pub backend: Arc<RecordingBackend>,
+    pub state: AppState,
+    task: tokio::task::JoinHandle<std::io::Result<()>>,
+}
+
+impl TestServer {
+    pub async fn start(mut config: ServerConfig) -> Self {
+        config
+            .access
+            .control_token
+            .get_or_insert_with(|| CONTROL_TOKEN.to_string());
+
+        let (server_tauri_tx, room_rx) = mpsc::channel(32);
+        let (server_event_tx, event_rx) = mpsc::channel(32);
+        let (tauri_client_tx, _) = broadcast::channel(16);
+
+        let backend = Arc::new(RecordingBackend::default());
+        let guard = CommandGuard::new(CommandPolicy {
+            unknown: UnknownCommand::Allow,
+            ..Default::default()
+        });
+        let registry = Registry::default();
+
+        let state = AppState::new(
+            server_tauri_tx,
+            tauri_client_tx.clone(),
+            server_event_tx,
+            CommandRunner::new(backend.clone(), guard),
+            registry.clone(),
+        )
+        .with_config(config);
+
+        let server = jojo_server::bind(Ipv4Addr::LOCALHOST, 0, state.clone())
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+
+        TestServer {
+            addr,
+            room_rx,
+            event_rx,
+            tauri_client_tx,
+            registry,
+            backend,
+            state,
+            task: tokio::spawn(server.serve()),
+        }
+    }
+
+    pub async fn connect(&self, device_id: DeviceId) -> TestClient {
+        let url = format!("ws://{}/ws/{}", self.addr, device_id);
+        let (socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+
+        TestClient {
+            socket,
+            encoding: Encoding::default(),
+        }
+    }
+
+    // Asks for the subprotocols in order, the server answers with the one it picked
+    pub async fn connect_with_subprotocols(
+        &self,
+        device_id: DeviceId,
+        subprotocols: &str,
+    ) -> (TestClient, Option<String>) {
+        let mut request = format!("ws://{}/ws/{}", self.addr, device_id)
+            .into_client_request()
+            .unwrap();
+        request
+            .headers_mut()
+            .insert("Sec-WebSocket-Protocol", subprotocols.parse().unwrap());
+
+        let (socket, response) = tokio_tungstenite::connect_async(request).await.unwrap();
+        let subprotocol = response
+            .headers()
+            .get("Sec-WebSocket-Protocol")
+            .map(|value| value.to_str().unwrap().to_string());
+
+        let encoding = subprotocol
+            .as_deref()
+            .and_then(Encoding::from_subprotocols)
+            .unwrap_or_default();
+
+        (TestClient { socket, encoding }, subprotocol)
+    }
+
+    // Websocket request with the control token
+    pub fn request(&self, path: &str) -> Request {
+        let mut request = format!("ws://{}{}", self.addr, path)
+            .into_client_request()
+            .unwrap();
+        request.headers_mut().insert(
+            "Authorization",
+            format!("Bearer {}", CONTROL_TOKEN).parse().unwrap(),
+        );
+
+        request
+    }
+
+    pub async fn control(&self) -> ControlClient {
+        let (socket, _) = tokio_tungstenite::connect_async(self.request("/control"))
+            .await
+            .unwrap();
+
+        ControlClient { socket }
+    }
+
+    // Plain http/1.1 request, returns the status line and the body
+    pub async fn get(&self, path: &str) -> (String, String) {
+        let mut stream = TcpStream::connect(self.addr).await.unwrap();
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, self.addr
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        within(stream.read_to_string(&mut response)).await.unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+
+        (head.lines().next().unwrap().to_string(), body.to_string())
+    }
+
+    pub async fn room_event(&mut self) -> RoomEvent {
+        within(self.room_rx.recv())
+            .await
+            .expect("room channel closed")
+    }
+
+    pub async fn server_event(&mut self) -> ServerEvent {
+        within(self.event_rx.recv())
+            .await
+            .expect("event channel closed")
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+pub struct TestClient {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    // Picked by the subprotocol or the welcome, bincode until then
+    encoding: Encoding,
+}
+
+impl TestClient {
+    // Registers the device like jojo-client does right after connecting
+    pub async fn handshake(&mut self, device: Device) {
+        self.send(&ClientMessage::Device(device)).await;
+    }
+
+    pub async fn send(&mut self, message: &ClientMessage) {
+        let bytes = protocol::encode_client_as(self.encoding, message).unwrap();
+
+        self.send_raw(self.frame(bytes)).await;
+    }
+
+    pub async fn send_raw(&mut self, message: Message) {
+        self.socket.send(message).await.unwrap();
+    }
+
+    // Next message from the server that isn't a ping
+    pub async fn recv(&mut self) -> Option<Message> {
+        within(async {
+            loop {
+                match self.socket.next().await {
+                    Some(Ok(Message::Ping(_))) => continue,
+                    Some(Ok(message)) => return Some(message),
+                    _ => return None,
+                }
+            }
+        })
+        .await
+    }
+
+    pub async fn recv_server_message(&mut self) -> ServerMessage {
+        match self.recv_outbound().await {
+            Outbound::Server(message) => message,
+            other => panic!("expected a server message, got {:?}", other),
+        }
+    }
+
+    pub async fn recv_extension(&mut self) -> ExtServerMessage {
+        match self.recv_outbound().await {
+            Outbound::Extension(message) => {
+                // Everything after the welcome uses the encoding it agreed on
+                if let ExtServerMessage::Welcome(peer) = &message {
+                    self.encoding = peer.encoding;
+                }
+
+                message
+            }
+            other => panic!("expected an extension message, got {:?}", other),
+        }
+    }
+
+    pub async fn send_extension(&mut self, message: &ExtClientMessage) {
+        let bytes = protocol::encode_extension_as(self.encoding, message).unwrap();
+
+        self.send_raw(self.frame(bytes)).await;
+    }
+
+    async fn recv_outbound(&mut self) -> Outbound {
+        let bytes = match self.recv().await {
+            Some(Message::Binary(bytes)) => bytes,
+            Some(Message::Text(text)) => text.into_bytes(),
+            other => panic!("expected a message from the server, got {:?}", other),
+        };
+
+        Outbound::decode_as(self.encoding, &bytes).unwrap()
+    }
+
+    // Json travels in text frames
+    fn frame(&self, bytes: Vec<u8>) -> Message {
+        match self.encoding.is_text() {
+            true => Message::Text(String::from_utf8(bytes).unwrap()),
+            false => Message::Binary(bytes),
+        }
+    }
+
+    // Reads for a while, answering the pings of the server
+    pub async fn idle(&mut self, duration: Duration) {
+        tokio::time::timeout(duration, async {
+            while let Some(Ok(_)) = self.socket.next().await {}
+        })
+        .await
+        .ok();
+    }
+
+    // True once the server closed the socket, either with a close frame or by dropping it
+    pub async fn closed(&mut self) -> bool {
+        matches!(self.recv().await, None | Some(Message::Close(_)))
+    }
+}
+
+pub struct ControlClient {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl ControlClient {
+    pub async fn send(&mut self, command: &ControlCommand) {
+        let text = serde_json::to_string(command).unwrap();
+
+        self.socket.send(Message::Text(text)).await.unwrap();
+    }
+
+    pub async fn recv(&mut self) -> ControlReply {
+        match within(self.socket.next()).await {
+            Some(Ok(Message::Text(text))) => serde_json::from_str(&text).unwrap(),
+            other => panic!("expected a control reply, got {:?}", other),
+        }
+    }
+}
+
+pub async fn within<T>(future: impl std::future::Future<Output = T>) -> T {
+    tokio::time::timeout(Duration::from_millis(WAIT_MILLIS), future)
+        .await
+        .expect("timed out")
+}
+
+pub fn device() -> (DeviceId, Device) {
+    let device = Device::default();
+
+    (device.id(), device)
+}
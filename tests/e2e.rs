@@ -0,0 +1,823 @@
+mod common;
+
+use common::{device, TestServer, CONTROL_TOKEN};
+use jojo_common::button::ButtonAction;
+use jojo_common::command::CustomCommand;
+use jojo_common::message::{ClientMessage, ServerMessage};
+use jojo_common::room::{RoomAction, RoomEvent};
+use jojo_server::access::AccessConfig;
+use jojo_server::command::CommandStatus;
+use jojo_server::config::ServerConfig;
+use jojo_server::control::{ControlCommand, ControlReply};
+use jojo_server::dispatch::{Target, Undelivered};
+use jojo_server::event::ControlEvent;
+use jojo_server::event::{DisconnectReason, RoomChange, ServerEvent};
+use jojo_server::limit::{Limits, Violation, ViolationPolicy};
+use jojo_server::ota::FirmwareProgress;
+use jojo_server::protocol::{
+    self, Capability, Encoding, ExtClientMessage, ExtServerMessage, Hello, Outbound,
+    PROTOCOL_VERSION,
+};
+use jojo_server::recorder::{RecordedInput, RecordedMessage};
+use jojo_server::telemetry::{Telemetry, TelemetryAlert};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::Message;
+
+#[tokio::test]
+async fn test_handshake_and_close() {
+    let mut server = TestServer::start(ServerConfig::default()).await;
+    let (device_id, device) = device();
+
+    let mut client = server.connect(device_id).await;
+    client.handshake(device.clone()).await;
+
+    assert_eq!(
+        server.room_event().await,
+        RoomEvent::new(device_id, RoomAction::Join)
+    );
+
+    let ServerEvent::Room(id, info, RoomChange::Joined(joined)) = server.server_event().await
+    else {
+        panic!("no joined event");
+    };
+
+    assert_eq!(id, device_id);
+    assert_eq!(joined, device);
+    assert!(info.remote_addr.is_some_and(|addr| addr.ip().is_loopback()));
+
+    client.send_raw(Message::Close(None)).await;
+
+    assert_eq!(
+        server.room_event().await,
+        RoomEvent::new(device_id, RoomAction::Leave)
+    );
+    assert_eq!(
+        server.server_event().await,
+        ServerEvent::Room(device_id, info, RoomChange::Left(DisconnectReason::Closed))
+    );
+    assert!(client.closed().await);
+}
+
+#[tokio::test]
+async fn test_replay_leaves_the_room() {
+    let mut server = TestServer::start(ServerConfig::default()).await;
+    let (device_id, device) = device();
+    let path = std::env::temp_dir().join(format!("{}.jsonl", device_id));
+
+    // Recording the device while it's replayed would append to the recording
+    server
+        .registry
+        .write()
+        .await
+        .start_recording(device_id, &path);
+
+    let recording = vec![RecordedMessage {
+        device_id,
+        elapsed_micros: 0,
+        message: RecordedInput::Client(ClientMessage::Device(device.clone())),
+    }];
+
+    jojo_server::handler::replay(recording, 1.0, &server.state).await;
+
+    assert_eq!(
+        server.room_event().await,
+        RoomEvent::new(device_id, RoomAction::Join)
+    );
+    assert_eq!(
+        server.room_event().await,
+        RoomEvent::new(device_id, RoomAction::Leave)
+    );
+
+    let ServerEvent::Room(_, info, RoomChange::Joined(_)) = server.server_event().await else {
+        panic!("no joined event");
+    };
+
+    assert_eq!(
+        server.server_event().await,
+        ServerEvent::Room(
+            device_id,
+            info,
+            RoomChange::Left(DisconnectReason::ReplayEnded)
+        )
+    );
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn test_reconnect_replaces_the_session() {
+    let mut server = TestServer::start(ServerConfig::default()).await;
+    let (device_id, device) = device();
+
+    let mut old_client = server.connect(device_id).await;
+    old_client.handshake(device.clone()).await;
+    server.room_event().await;
+
+    let ServerEvent::Room(_, old_info, RoomChange::Joined(_)) = server.server_event().await else {
+        panic!("no joined event");
+    };
+
+    let mut client = server.connect(device_id).await;
+
+    assert_eq!(
+        server.server_event().await,
+        ServerEvent::Room(
+            device_id,
+            old_info,
+            RoomChange::Left(DisconnectReason::Replaced)
+        )
+    );
+
+    // Sending the device again doesn't join a second time
+    client.handshake(device.clone()).await;
+
+    let ServerEvent::Room(_, info, RoomChange::Updated(updated)) = server.server_event().await
+    else {
+        panic!("no updated event");
+    };
+
+    assert_ne!(info.session_id, old_info.session_id);
+    assert_eq!(updated, device);
+    assert!(old_client.closed().await);
+
+    client.send_raw(Message::Close(None)).await;
+
+    assert_eq!(
+        server.room_event().await,
+        RoomEvent::new(device_id, RoomAction::Leave)
+    );
+}
+
+#[tokio::test]
+async fn test_timeout_without_pongs() {
+    let mut server = TestServer::start(ServerConfig {
+        timeout_millis: 200,
+        ping_millis: 50,
+        ..Default::default()
+    })
+    .await;
+    let (device_id, device) = device();
+
+    // The client never reads, so the pings are never answered
+    let mut client = server.connect(device_id).await;
+    client.handshake(device).await;
+
+    assert_eq!(
+        server.room_event().await,
+        RoomEvent::new(device_id, RoomAction::Join)
+    );
+    assert_eq!(
+        server.room_event().await,
+        RoomEvent::new(device_id, RoomAction::Leave)
+    );
+
+    server.server_event().await;
+
+    assert!(matches!(
+        server.server_event().await,
+        ServerEvent::Room(_, _, RoomChange::Left(DisconnectReason::Timeout))
+    ));
+}
+
+#[tokio::test]
+async fn test_malformed_payloads_are_ignored() {
+    let mut server = TestServer::start(ServerConfig::default()).await;
+    let (device_id, device) = device();
+
+    let mut client = server.connect(device_id).await;
+    client.handshake(device).await;
+    server.room_event().await;
+    server.server_event().await;
+
+    client.send_raw(Message::Binary(vec![0xFF; 7])).await;
+    client
+        .send_raw(Message::Text("{not json".to_string()))
+        .await;
+    client
+        .send(&ClientMessage::ButtonActions(vec![
+            ButtonAction::CustomButton(CustomCommand::Binary("jojo-e2e".to_string())),
+        ]))
+        .await;
+
+    match server.server_event().await {
+        ServerEvent::CommandReport(id, report) => {
+            assert_eq!(id, device_id);
+            assert_eq!(report.status, CommandStatus::Exited(Some(0)));
+        }
+        event => panic!("unexpected event {:?}", event),
+    }
+
+    let runs = server.backend.runs.lock().unwrap().clone();
+
+    assert_eq!(runs.len(), 1);
+    assert_eq!(runs[0].program, "jojo-e2e");
+}
+
+#[tokio::test]
+async fn test_update_device_reaches_the_client() {
+    let mut server = TestServer::start(ServerConfig::default()).await;
+    let (device_id, device) = device();
+
+    let mut client = server.connect(device_id).await;
+    client.handshake(device).await;
+    server.room_event().await;
+
+    server
+        .tauri_client_tx
+        .send(ServerMessage::UpdateDevice(device_id, HashMap::new()))
+        .unwrap();
+
+    assert!(matches!(
+        client.recv_server_message().await,
+        ServerMessage::UpdateDevice(id, _) if id == device_id
+    ));
+
+    // The registry keeps the mappings too
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(server.registry.read().await.get(&device_id).is_some());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_local_socket() {
+    use futures_util::SinkExt;
+
+    let mut server = TestServer::start(ServerConfig::default()).await;
+    let (device_id, device) = device();
+    let path = std::env::temp_dir().join(format!("jojo-{}.sock", uuid::Uuid::new_v4()));
+
+    let local_server = jojo_server::bind_local(&path, server.state.clone())
+        .await
+        .unwrap();
+    let local_task = tokio::spawn(local_server.serve());
+
+    let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+    let (mut socket, _) =
+        tokio_tungstenite::client_async(format!("ws://localhost/ws/{}", device_id), stream)
+            .await
+            .unwrap();
+
+    socket
+        .send(Message::Binary(
+            bincode::serialize(&ClientMessage::Device(device)).unwrap(),
+        ))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        server.room_event().await,
+        RoomEvent::new(device_id, RoomAction::Join)
+    );
+
+    // The socket is only reachable by the user running the server, it doesn't ask for the control token
+    let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+    assert!(
+        tokio_tungstenite::client_async("ws://localhost/control", stream)
+            .await
+            .is_ok()
+    );
+
+    local_task.abort();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn test_control_endpoint() {
+    let mut server = TestServer::start(ServerConfig::default()).await;
+    let (device_id, device) = device();
+
+    let mut control = server.control().await;
+    control.send(&ControlCommand::ListDevices).await;
+
+    assert_eq!(control.recv().await, ControlReply::Devices(Vec::new()));
+
+    let mut client = server.connect(device_id).await;
+    client.handshake(device).await;
+    server.room_event().await;
+
+    assert_eq!(
+        control.recv().await,
+        ControlReply::Event(ControlEvent::Joined(device_id))
+    );
+    assert!(matches!(
+        control.recv().await,
+        ControlReply::Event(ControlEvent::Server(ServerEvent::Room(
+            _,
+            _,
+            RoomChange::Joined(_)
+        )))
+    ));
+
+    control
+        .send(&ControlCommand::RestartDevice(Target::Device(device_id)))
+        .await;
+
+    assert!(matches!(
+        control.recv().await,
+        ControlReply::Delivery(delivery) if delivery.delivered == vec![device_id]
+    ));
+    assert!(matches!(
+        client.recv_server_message().await,
+        ServerMessage::RestartDevice(id) if id == device_id
+    ));
+
+    // Devices of a group that aren't connected are reported, the others still get the message
+    let offline = uuid::Uuid::new_v4();
+
+    control
+        .send(&ControlCommand::SetGroup(
+            "desk".to_string(),
+            vec![device_id, offline],
+        ))
+        .await;
+
+    assert_eq!(control.recv().await, ControlReply::Done);
+
+    control
+        .send(&ControlCommand::ClearCredentials(Target::Group(
+            "desk".to_string(),
+        )))
+        .await;
+
+    let ControlReply::Delivery(delivery) = control.recv().await else {
+        panic!("no delivery reply");
+    };
+
+    assert_eq!(delivery.delivered, vec![device_id]);
+    assert_eq!(
+        delivery.undelivered,
+        vec![(offline, Undelivered::NotConnected)]
+    );
+    assert!(matches!(
+        client.recv_server_message().await,
+        ServerMessage::ClearCredentials(id) if id == device_id
+    ));
+
+    control.send(&ControlCommand::ListDevices).await;
+
+    assert!(matches!(control.recv().await, ControlReply::Devices(devices) if devices.len() == 1));
+
+    // The recording backend never leaves a process behind
+    control.send(&ControlCommand::RunningCommands).await;
+
+    assert_eq!(
+        control.recv().await,
+        ControlReply::RunningCommands(Vec::new())
+    );
+}
+
+#[tokio::test]
+async fn test_telemetry() {
+    let mut server = TestServer::start(ServerConfig::default()).await;
+    let (device_id, device) = device();
+
+    let mut client = server.connect(device_id).await;
+    client
+        .send_extension(&ExtClientMessage::Hello(Hello {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: vec![Capability::Telemetry],
+            encodings: vec![Encoding::Bincode],
+        }))
+        .await;
+    client.recv_extension().await;
+    client.handshake(device).await;
+    server.room_event().await;
+    server.server_event().await;
+
+    let telemetry = Telemetry {
+        battery_percent: 5,
+        charging: false,
+        rssi_dbm: -40,
+        free_heap_bytes: 120_000,
+        firmware_version: "0.1.0".to_string(),
+        uptime_secs: 30,
+    };
+
+    client
+        .send_extension(&ExtClientMessage::Telemetry(telemetry.clone()))
+        .await;
+
+    assert_eq!(
+        server.server_event().await,
+        ServerEvent::Telemetry(device_id, TelemetryAlert::LowBattery(5))
+    );
+
+    let mut control = server.control().await;
+    control.send(&ControlCommand::Telemetry(device_id)).await;
+
+    let ControlReply::Telemetry(id, history) = control.recv().await else {
+        panic!("no telemetry reply");
+    };
+
+    assert_eq!(id, device_id);
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].telemetry, telemetry);
+}
+
+#[tokio::test]
+async fn test_firmware_update() {
+    let mut server = TestServer::start(ServerConfig {
+        firmware_dir: Some(std::env::temp_dir()),
+        ..Default::default()
+    })
+    .await;
+    let (device_id, device) = device();
+    let path = std::env::temp_dir().join(format!("jojo-{}.bin", uuid::Uuid::new_v4()));
+    std::fs::write(&path, vec![0x42; 5_000]).unwrap();
+
+    let mut client = server.connect(device_id).await;
+    client
+        .send_extension(&ExtClientMessage::Hello(Hello {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: vec![Capability::Firmware],
+            encodings: vec![Encoding::Bincode],
+        }))
+        .await;
+
+    assert!(matches!(
+        client.recv_extension().await,
+        ExtServerMessage::Welcome(peer) if peer.capabilities == vec![Capability::Firmware]
+    ));
+
+    client.handshake(device).await;
+    server.room_event().await;
+    server.server_event().await;
+
+    let mut control = server.control().await;
+    control
+        .send(&ControlCommand::UpdateFirmware {
+            target: Target::Device(device_id),
+            path: path.clone(),
+            version: Some("0.2.0".to_string()),
+        })
+        .await;
+
+    assert!(matches!(
+        control.recv().await,
+        ControlReply::Delivery(delivery) if delivery.delivered == vec![device_id]
+    ));
+    std::fs::remove_file(&path).unwrap();
+
+    let ExtServerMessage::FirmwareOffer {
+        update_id,
+        size,
+        sha256,
+        ..
+    } = client.recv_extension().await
+    else {
+        panic!("no firmware offer");
+    };
+
+    assert_eq!(size, 5_000);
+    assert!(matches!(
+        server.server_event().await,
+        ServerEvent::Firmware(_, FirmwareProgress::Offered { version, .. }) if version == "0.2.0"
+    ));
+
+    // Write every chunk and ack the next offset, like the device does
+    let mut image = Vec::new();
+    let mut offset = 0;
+
+    while offset < size {
+        client
+            .send_extension(&ExtClientMessage::FirmwareAck(update_id, offset))
+            .await;
+
+        let ExtServerMessage::FirmwareChunk { data, .. } = client.recv_extension().await else {
+            panic!("no firmware chunk");
+        };
+
+        offset += data.len() as u64;
+        image.extend(data);
+    }
+
+    client
+        .send_extension(&ExtClientMessage::FirmwareAck(update_id, offset))
+        .await;
+    client
+        .send_extension(&ExtClientMessage::FirmwareDigest(update_id, sha256))
+        .await;
+
+    assert_eq!(image, vec![0x42; 5_000]);
+    assert_eq!(
+        client.recv_extension().await,
+        ExtServerMessage::FirmwareFinalize(update_id)
+    );
+
+    loop {
+        match server.server_event().await {
+            ServerEvent::Firmware(_, FirmwareProgress::Transferring { .. }) => {}
+            ServerEvent::Firmware(_, FirmwareProgress::Completed(id)) => {
+                assert_eq!(id, update_id);
+                break;
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_hello() {
+    let mut server = TestServer::start(ServerConfig::default()).await;
+    let (device_id, device) = device();
+
+    // Json was asked first, so the welcome comes as text
+    let mut client = server.connect(device_id).await;
+    client
+        .send_extension(&ExtClientMessage::Hello(Hello {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: vec![Capability::Telemetry],
+            encodings: vec![Encoding::Json, Encoding::Bincode],
+        }))
+        .await;
+
+    let Some(Message::Text(text)) = client.recv().await else {
+        panic!("no welcome");
+    };
+
+    assert!(matches!(
+        serde_json::from_str::<ExtServerMessage>(&text).unwrap(),
+        ExtServerMessage::Welcome(peer) if peer.encoding == Encoding::Json
+    ));
+
+    client.handshake(device).await;
+    server.room_event().await;
+
+    // A client from the future is refused with the reason
+    let mut client = server.connect(uuid::Uuid::new_v4()).await;
+    client
+        .send_extension(&ExtClientMessage::Hello(Hello {
+            protocol_version: PROTOCOL_VERSION + 1,
+            capabilities: Vec::new(),
+            encodings: vec![Encoding::Bincode],
+        }))
+        .await;
+
+    let Some(Message::Close(Some(frame))) = client.recv().await else {
+        panic!("no close frame");
+    };
+
+    assert_eq!(u16::from(frame.code), 1002);
+    assert!(frame.reason.contains("protocol version"));
+
+    // The first message already started the session
+    let (device_id, device) = device();
+    let mut client = server.connect(device_id).await;
+    client.handshake(device).await;
+    server.room_event().await;
+    client
+        .send_extension(&ExtClientMessage::Hello(Hello {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Vec::new(),
+            encodings: vec![Encoding::Bincode],
+        }))
+        .await;
+
+    let Some(Message::Close(Some(frame))) = client.recv().await else {
+        panic!("no close frame");
+    };
+
+    assert!(frame.reason.contains("first message"));
+}
+
+#[tokio::test]
+async fn test_subprotocol_encoding() {
+    let mut server = TestServer::start(ServerConfig::default()).await;
+    let (device_id, device) = device();
+
+    let (mut client, subprotocol) = server
+        .connect_with_subprotocols(device_id, "chat, jojo.msgpack, jojo.json")
+        .await;
+
+    assert_eq!(subprotocol.as_deref(), Some("jojo.msgpack"));
+
+    client
+        .send_raw(Message::Binary(
+            protocol::encode_client_as(Encoding::MessagePack, &ClientMessage::Device(device))
+                .unwrap(),
+        ))
+        .await;
+
+    assert_eq!(
+        server.room_event().await,
+        RoomEvent::new(device_id, RoomAction::Join)
+    );
+
+    server
+        .tauri_client_tx
+        .send(ServerMessage::RestartDevice(device_id))
+        .unwrap();
+
+    let Some(Message::Binary(bytes)) = client.recv().await else {
+        panic!("no binary frame");
+    };
+
+    assert!(matches!(
+        Outbound::decode_as(Encoding::MessagePack, &bytes),
+        Ok(Outbound::Server(ServerMessage::RestartDevice(id))) if id == device_id
+    ));
+
+    // A hello can't switch away from the encoding of the subprotocol
+    let (mut client, _) = server
+        .connect_with_subprotocols(uuid::Uuid::new_v4(), "jojo.cbor")
+        .await;
+    client
+        .send_raw(Message::Binary(
+            protocol::encode_extension_as(
+                Encoding::Cbor,
+                &ExtClientMessage::Hello(Hello {
+                    protocol_version: PROTOCOL_VERSION,
+                    capabilities: Vec::new(),
+                    encodings: vec![Encoding::Bincode],
+                }),
+            )
+            .unwrap(),
+        ))
+        .await;
+
+    let Some(Message::Close(Some(frame))) = client.recv().await else {
+        panic!("no close frame");
+    };
+
+    assert!(frame.reason.contains("subprotocol"));
+}
+
+#[tokio::test]
+async fn test_rate_limit_disconnect() {
+    let mut server = TestServer::start(ServerConfig {
+        limits: Limits {
+            messages_per_second: 0.1,
+            burst: 2,
+            policy: ViolationPolicy::Disconnect,
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+    .await;
+    let (device_id, device) = device();
+
+    let mut client = server.connect(device_id).await;
+    client.handshake(device.clone()).await;
+    server.room_event().await;
+    server.server_event().await;
+
+    client.handshake(device.clone()).await;
+    client.handshake(device).await;
+
+    let Some(Message::Close(Some(frame))) = client.recv().await else {
+        panic!("no close frame");
+    };
+
+    assert_eq!(u16::from(frame.code), 1008);
+    assert!(matches!(
+        server.server_event().await,
+        ServerEvent::Room(_, _, RoomChange::Updated(_))
+    ));
+    assert!(matches!(
+        server.server_event().await,
+        ServerEvent::Room(
+            _,
+            _,
+            RoomChange::Left(DisconnectReason::Violation(Violation::RateExceeded))
+        )
+    ));
+}
+
+#[tokio::test]
+async fn test_denied_address() {
+    let server = TestServer::start(ServerConfig {
+        access: AccessConfig {
+            deny: vec!["127.0.0.0/8".parse().unwrap()],
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+    .await;
+    let (device_id, _) = device();
+
+    let url = format!("ws://{}/ws/{}", server.addr, device_id);
+
+    match tokio_tungstenite::connect_async(url).await {
+        Err(tokio_tungstenite::tungstenite::Error::Http(response)) => {
+            assert_eq!(response.status(), 403)
+        }
+        other => panic!("expected a rejection, got {:?}", other.map(|_| ())),
+    }
+
+    // Every route, not just device sockets, and before the token
+    assert!(server.get("/metrics").await.0.contains("403"));
+    assert!(server.get("/").await.0.contains("403"));
+}
+
+#[tokio::test]
+async fn test_browser_origin() {
+    let server = TestServer::start(ServerConfig {
+        access: AccessConfig {
+            allowed_origins: vec!["http://localhost:1420".to_string()],
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+    .await;
+    let (device_id, _) = device();
+
+    let connect = |path: String, origin: &'static str| {
+        let mut request = server.request(&path);
+        request
+            .headers_mut()
+            .insert("Origin", origin.parse().unwrap());
+
+        tokio_tungstenite::connect_async(request)
+    };
+
+    for path in [format!("/ws/{}", device_id), "/control".to_string()] {
+        match connect(path.clone(), "https://evil.example").await {
+            Err(tokio_tungstenite::tungstenite::Error::Http(response)) => {
+                assert_eq!(response.status(), 403)
+            }
+            other => panic!("expected a rejection, got {:?}", other.map(|_| ())),
+        }
+
+        assert!(connect(path, "http://localhost:1420").await.is_ok());
+    }
+
+    // Devices don't send an origin
+    server.connect(device_id).await;
+}
+
+#[tokio::test]
+async fn test_dashboard() {
+    let mut server = TestServer::start(ServerConfig {
+        ping_millis: 50,
+        ..Default::default()
+    })
+    .await;
+    let (device_id, device) = device();
+
+    let (status, body) = server.get(&format!("/?token={}", CONTROL_TOKEN)).await;
+
+    assert!(status.contains("200"));
+    assert!(body.contains("/control"));
+
+    let mut client = server.connect(device_id).await;
+    client.handshake(device).await;
+    server.room_event().await;
+    server.server_event().await;
+
+    client.idle(Duration::from_millis(300)).await;
+
+    // The page opens /control from the origin it was served from
+    let mut request = server.request("/control");
+    request
+        .headers_mut()
+        .insert("Origin", format!("http://{}", server.addr).parse().unwrap());
+    let (socket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+    drop(socket);
+
+    let mut control = server.control().await;
+    control.send(&ControlCommand::Status).await;
+
+    let ControlReply::Status(statuses) = control.recv().await else {
+        panic!("no status reply");
+    };
+
+    assert_eq!(statuses.len(), 1);
+    assert_eq!(statuses[0].device_id, device_id);
+    assert!(statuses[0].connected);
+    assert!(statuses[0].rtt_millis.is_some());
+    assert_eq!(statuses[0].telemetry, None);
+}
+
+#[tokio::test]
+async fn test_control_token() {
+    let server = TestServer::start(ServerConfig::default()).await;
+
+    let url = format!("ws://{}/control", server.addr);
+
+    match tokio_tungstenite::connect_async(url).await {
+        Err(tokio_tungstenite::tungstenite::Error::Http(response)) => {
+            assert_eq!(response.status(), 401)
+        }
+        other => panic!("expected a rejection, got {:?}", other.map(|_| ())),
+    }
+
+    assert!(server.get("/metrics").await.0.contains("401"));
+    assert!(server.get("/metrics?token=wrong").await.0.contains("401"));
+    assert!(server
+        .get(&format!("/metrics?token={}", CONTROL_TOKEN))
+        .await
+        .0
+        .contains("200"));
+
+    // Without a token the operator routes stay off the network
+    let server = TestServer::start(ServerConfig {
+        access: AccessConfig {
+            control_token: Some(String::new()),
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+    .await;
+
+    assert!(server.get("/").await.0.contains("403"));
+}